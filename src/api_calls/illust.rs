@@ -1,25 +1,65 @@
+use std::time::SystemTime;
+
 use serde::{Deserialize, Serialize};
 
-use super::{de_id, ApiError, Root};
+use super::{de_id, parse_date, ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn _get(client: SemaphoredClient, illust_id: u64) -> Result<IllustInfo, ApiError> {
-    Root::query(
-        client,
-        &format!("https://www.pixiv.net/ajax/illust/{}", illust_id),
-    )
-    .await
+pub(crate) async fn get(client: SemaphoredClient, illust_id: u64) -> Result<IllustInfo, ApiError> {
+    Root::query(client, &format!("/ajax/illust/{}", illust_id)).await
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct IllustInfo {
     pub illust_title: String,
-    /// 1 for Illust, 2 for Ugoira
+    /// 0 for illust, 1 for manga, 2 for ugoira
     pub illust_type: usize,
     pub create_date: String,
     pub upload_date: String,
     #[serde(deserialize_with = "de_id")]
     pub user_id: u64,
+    pub user_name: String,
     pub page_count: usize,
+    pub tags: Tags,
+    /// Set if the illust is part of a series
+    pub series_nav_data: Option<SeriesNavData>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tags {
+    pub tags: Vec<Tag>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tag {
+    pub tag: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesNavData {
+    #[serde(deserialize_with = "de_id")]
+    pub series_id: u64,
+    pub title: String,
+    /// Position of the illust in the series
+    pub order: usize,
+}
+
+impl IllustInfo {
+    /// When the illust was first posted
+    pub fn created_at(&self) -> Option<SystemTime> {
+        parse_date(&self.create_date)
+    }
+
+    /// When the illust was last modified
+    pub fn uploaded_at(&self) -> Option<SystemTime> {
+        parse_date(&self.upload_date)
+    }
+
+    pub fn is_manga(&self) -> bool {
+        self.illust_type == 1
+    }
 }
@@ -1,21 +1,31 @@
-use reqwest::StatusCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::{header::RETRY_AFTER, StatusCode};
 use serde::{
     de::{self, DeserializeOwned},
     Deserialize, Deserializer, Serialize,
 };
-use serde_json::{from_slice, from_value, Value};
+use serde_json::{error::Category, from_slice, Value};
 use thiserror::Error;
+use tracing::{debug, warn};
 
-use crate::gen_http_client::SemaphoredClient;
+use crate::{
+    gen_http_client::SemaphoredClient,
+    transport::{Response, TransportError},
+};
 
 pub mod illust;
 pub mod illust_pages;
 pub mod novel;
+pub mod novel_series;
+pub mod ranking;
+pub mod search;
 pub mod series;
 pub mod ugoira_meta;
 pub mod user_bookmarks;
 pub mod user_illustmanga_tag;
 pub mod user_info;
+pub mod vanity;
 
 // -----
 
@@ -28,15 +38,10 @@ pub struct Root<T> {
 }
 
 impl<T: Serialize + DeserializeOwned> Root<T> {
-    pub async fn query(client: SemaphoredClient, url: &str) -> Result<T, ApiError> {
-        let permit = client.semaphore.acquire().await.unwrap(); // TODO: Handle this unwrap properly ?
-
-        let req = client.client.get(url);
-        let resp = req.send().await.map_err(ApiError::Network)?;
-        let status_code = resp.status();
-        let full = resp.bytes().await.map_err(ApiError::Network)?;
-
-        drop(permit); // TODO: Move drop higher ?
+    /// Query an API endpoint. Path is relative to the configured API origin
+    pub(crate) async fn query(client: SemaphoredClient, path: &str) -> Result<T, ApiError> {
+        let resp = fetch(&client, path).await?;
+        let (status_code, full) = (resp.status, resp.body);
 
         // Check for empty response
         if full.is_empty() {
@@ -60,12 +65,106 @@ impl<T: Serialize + DeserializeOwned> Root<T> {
         }
 
         // Parse body next
-        let body: T = from_value(root.body).map_err(ApiError::JSONParse)?;
+        serde_path_to_error::deserialize(root.body).map_err(ApiError::from_path_error)
+    }
+}
+
+/// Query an endpoint that returns its data as is, without the usual root. Path is relative to the configured API origin
+pub(crate) async fn query_plain<T: DeserializeOwned>(
+    client: SemaphoredClient,
+    path: &str,
+) -> Result<T, ApiError> {
+    let resp = fetch(&client, path).await?;
+    let (status_code, full) = (resp.status, resp.body);
+
+    if full.is_empty() {
+        return Err(ApiError::EmptyResponse { status_code });
+    }
+    if status_code != StatusCode::OK {
+        return Err(ApiError::ServerHTTP { status_code });
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_slice(&full);
+    serde_path_to_error::deserialize(&mut deserializer).map_err(ApiError::from_path_error)
+}
+
+/// Get raw response from server, or from recording when replaying. Refusals that can be told apart are turned into errors
+async fn fetch(client: &SemaphoredClient, path: &str) -> Result<Response, ApiError> {
+    fetch_url(client, path, &client.endpoints.api_url(path)).await
+}
+
+/// Like `fetch`, for a URL outside of the API. Path is only used to name it in recordings and logs
+async fn fetch_url(client: &SemaphoredClient, path: &str, url: &str) -> Result<Response, ApiError> {
+    let resp = match &client.recording {
+        Some(r) if r.is_replay() => {
+            debug!(path, "replaying");
+            r.load_response(path)
+                .await
+                .map_err(ApiError::Recording)?
+                .ok_or_else(|| ApiError::NotRecorded {
+                    path: path.to_string(),
+                })?
+        }
+        _ => {
+            let permit = client.api_permit().await;
+            client.throttle().await;
+
+            let resp = client.transport.get(url).await.map_err(ApiError::Network)?;
 
-        Ok(body)
+            drop(permit); // TODO: Move drop higher ?
+
+            // Keyed by path so that recordings don't depend on API origin
+            if let Some(r) = &client.recording {
+                r.save_response(path, &resp)
+                    .await
+                    .map_err(ApiError::Recording)?;
+            }
+
+            resp
+        }
+    };
+
+    if !resp.status.is_success() {
+        warn!(path, status = %resp.status, "API request refused");
+    }
+
+    match refusal(&resp, client.logged_in) {
+        Some(e) => Err(e),
+        None => Ok(resp),
     }
 }
 
+/// Most specific error for a response, if its status tells why it was refused
+fn refusal(resp: &Response, logged_in: bool) -> Option<ApiError> {
+    let status_code = resp.status;
+    // Explanation is in the usual root, when there is one
+    let message = from_slice::<Root<Value>>(&resp.body)
+        .ok()
+        .map(|r| r.message)
+        .filter(|m| !m.is_empty())
+        .unwrap_or_else(|| status_code.to_string());
+
+    Some(match status_code {
+        StatusCode::NOT_FOUND => ApiError::NotFound { message },
+        StatusCode::UNAUTHORIZED if logged_in => ApiError::ExpiredSession { message },
+        // Anonymous users are refused what logged-in ones could see
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if !logged_in => ApiError::LoginRequired {
+            message,
+            status_code,
+        },
+        StatusCode::FORBIDDEN => ApiError::Forbidden { message },
+        StatusCode::TOO_MANY_REQUESTS => ApiError::RateLimited {
+            retry_after: resp
+                .headers
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs),
+        },
+        _ => return None,
+    })
+}
+
 // -----
 
 // trait PartialResults {}
@@ -74,21 +173,104 @@ impl<T: Serialize + DeserializeOwned> Root<T> {
 
 // -----
 
+/// Everything that can go wrong when querying the API
 #[derive(Error, Debug)]
 pub enum ApiError {
+    /// Request couldn't be sent, or the response couldn't be received
     #[error("problem with http/network")]
-    Network(#[source] reqwest::Error),
+    Network(#[source] TransportError),
+    /// Response had no body at all
     #[error("server returned an empty response with code {status_code}")]
     EmptyResponse { status_code: StatusCode },
+    /// Response wasn't valid JSON
     #[error("couldn't parse received json")]
     JSONParse(#[source] serde_json::Error),
+    /// Response was JSON, but not shaped as expected for this endpoint. The API may have changed
+    #[error("unexpected json at `{path}`")]
+    SchemaDrift {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// Work or user doesn't exist, or was deleted
+    #[error("not found: {message}")]
+    NotFound { message: String },
+    /// Only logged-in users can see this, e.g. R-18 works
+    #[error("login required: {message}")]
+    LoginRequired {
+        message: String,
+        status_code: StatusCode,
+    },
+    /// This account can't see this, e.g. because of its age or display settings
+    #[error("forbidden for this account: {message}")]
+    Forbidden { message: String },
+    /// Too many requests in a short time
+    #[error("rate limited by server")]
+    RateLimited { retry_after: Option<Duration> },
+    /// Cookie isn't accepted anymore, a new one is needed
+    #[error("session expired, the cookie must be renewed: {message}")]
+    ExpiredSession { message: String },
+    /// Server refused the request, with an explanation. e.g. a work that doesn't exist
     #[error("server returned \"{message}\" ({status_code})")]
     ServerApplication {
         message: String,
         status_code: StatusCode,
     },
+    /// Server refused the request without an explanation
     #[error("server returned {status_code}")]
     ServerHTTP { status_code: StatusCode },
+    /// Replaying, and this request wasn't part of the recording
+    #[error("no recorded response for {path}")]
+    NotRecorded { path: String },
+    /// Recording couldn't be read or written
+    #[error("couldn't access recording")]
+    Recording(#[source] std::io::Error),
+}
+
+impl ApiError {
+    /// Short name of the kind of error, stable for scripts
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Network(_) => "network",
+            ApiError::EmptyResponse { .. } => "empty_response",
+            ApiError::JSONParse(_) => "json_parse",
+            ApiError::SchemaDrift { .. } => "schema_drift",
+            ApiError::NotFound { .. } => "not_found",
+            ApiError::LoginRequired { .. } => "login_required",
+            ApiError::Forbidden { .. } => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::ExpiredSession { .. } => "expired_session",
+            ApiError::ServerApplication { .. } => "server_application",
+            ApiError::ServerHTTP { .. } => "server_http",
+            ApiError::NotRecorded { .. } => "not_recorded",
+            ApiError::Recording(_) => "recording",
+        }
+    }
+
+    /// HTTP status of the response, if there was one
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            ApiError::EmptyResponse { status_code }
+            | ApiError::ServerApplication { status_code, .. }
+            | ApiError::ServerHTTP { status_code }
+            | ApiError::LoginRequired { status_code, .. } => Some(*status_code),
+            ApiError::NotFound { .. } => Some(StatusCode::NOT_FOUND),
+            ApiError::Forbidden { .. } => Some(StatusCode::FORBIDDEN),
+            ApiError::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            ApiError::ExpiredSession { .. } => Some(StatusCode::UNAUTHORIZED),
+            _ => None,
+        }
+    }
+
+    /// Tell apart JSON that doesn't match what was expected from invalid JSON
+    fn from_path_error(e: serde_path_to_error::Error<serde_json::Error>) -> ApiError {
+        let path = e.path().to_string();
+        let source = e.into_inner();
+        match source.classify() {
+            Category::Data => ApiError::SchemaDrift { path, source },
+            _ => ApiError::JSONParse(source),
+        }
+    }
 }
 
 // -----
@@ -118,3 +300,117 @@ fn de_id_map<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::
         _ => return Err(de::Error::custom("wrong type")),
     })
 }
+
+/// Percent-encode text for use in a path segment or query value, e.g. a tag
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Parse dates as given by the API, e.g. `2020-01-02T03:04:05+09:00`
+fn parse_date(s: &str) -> Option<SystemTime> {
+    let (date, rest) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    // Offset from UTC follows time
+    let (time, offset) = match rest.find(['+', '-', 'Z']) {
+        Some(i) => rest.split_at(i),
+        None => (rest, "Z"),
+    };
+    let mut time = time.splitn(3, ':');
+    let hours: i64 = time.next()?.parse().ok()?;
+    let minutes: i64 = time.next()?.parse().ok()?;
+    // Ignore fractions of seconds
+    let seconds: i64 = time.next()?.split('.').next()?.parse().ok()?;
+
+    let offset = if offset == "Z" {
+        0
+    } else {
+        let (h, m) = offset[1..].split_once(':')?;
+        let secs = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+        if offset.starts_with('-') {
+            -secs
+        } else {
+            secs
+        }
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds - offset;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// -----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_from_api_are_parsed() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_date("1970-01-01T00:00:00+00:00"), at(0));
+        assert_eq!(parse_date("2020-01-02T03:04:05+09:00"), at(1577901845));
+        assert_eq!(parse_date("2020-01-01T18:04:05Z"), at(1577901845));
+        assert_eq!(parse_date("2024-02-29T12:00:00.123-05:00"), at(1709226000));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn refusals_are_told_apart() {
+        use std::sync::Arc;
+
+        use crate::{
+            gen_http_client::{Endpoints, Limits},
+            transport::MemoryTransport,
+        };
+
+        let url = |p: &str| format!("https://www.pixiv.net/ajax/illust/{}/pages", p);
+        let mut transport = MemoryTransport::default();
+        let refused = r#"{"error":true,"message":"gone","body":[]}"#;
+        transport.insert(&url("1"), StatusCode::NOT_FOUND, refused);
+        transport.insert(&url("2"), StatusCode::FORBIDDEN, refused);
+        transport.insert(&url("3"), StatusCode::TOO_MANY_REQUESTS, "");
+        let drifted = r#"{"error":false,"message":"","body":[{"urls":{},"width":1,"height":1}]}"#;
+        transport.insert(&url("4"), StatusCode::OK, drifted);
+
+        let mut client = SemaphoredClient::new(
+            Arc::new(transport),
+            Limits::default(),
+            Endpoints::default(),
+            None,
+        );
+        let get = |client: &SemaphoredClient, id| illust_pages::get(client.clone(), id);
+
+        let e = get(&client, 1).await.unwrap_err();
+        assert!(matches!(e, ApiError::NotFound { message } if message == "gone"));
+        let e = get(&client, 2).await.unwrap_err();
+        assert!(matches!(e, ApiError::LoginRequired { .. }));
+        let e = get(&client, 3).await.unwrap_err();
+        assert!(matches!(e, ApiError::RateLimited { .. }));
+        let e = get(&client, 4).await.unwrap_err();
+        assert!(matches!(e, ApiError::SchemaDrift { path, .. } if path == "[0].urls"));
+
+        client.logged_in = true;
+        let e = get(&client, 2).await.unwrap_err();
+        assert!(matches!(e, ApiError::Forbidden { .. }));
+    }
+}
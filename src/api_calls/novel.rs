@@ -5,12 +5,8 @@ use serde::{Deserialize, Serialize};
 use super::{ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn get(client: SemaphoredClient, novel_id: u64) -> Result<NovelInfo, ApiError> {
-    Root::query(
-        client,
-        &format!("https://www.pixiv.net/ajax/novel/{}", novel_id),
-    )
-    .await
+pub(crate) async fn get(client: SemaphoredClient, novel_id: u64) -> Result<NovelInfo, ApiError> {
+    Root::query(client, &format!("/ajax/novel/{}", novel_id)).await
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
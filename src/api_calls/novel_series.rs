@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use super::{de_id, ApiError, Root};
+use crate::gen_http_client::SemaphoredClient;
+
+pub(crate) async fn get(client: SemaphoredClient, series_id: u64) -> Result<SeriesInfo, ApiError> {
+    Root::query(client, &format!("/ajax/novel/series/{}", series_id)).await
+}
+
+/// Novels of a series in order, after the first `last_order` of them
+pub(crate) async fn get_contents(
+    client: SemaphoredClient,
+    series_id: u64,
+    last_order: usize,
+    limit: usize,
+) -> Result<Contents, ApiError> {
+    Root::query(
+        client,
+        &format!(
+            "/ajax/novel/series_content/{}?limit={}&last_order={}&order_by=asc",
+            series_id, limit, last_order
+        ),
+    )
+    .await
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesInfo {
+    pub title: String,
+    pub caption: String,
+    /// Number of novels in the series
+    pub total: usize,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contents {
+    pub page: Page,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    pub series_contents: Vec<SeriesNovel>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesNovel {
+    #[serde(deserialize_with = "de_id")]
+    pub id: u64,
+    pub series: NovelPos,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NovelPos {
+    pub content_order: usize,
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+use super::{encode, query_plain, ApiError};
+use crate::gen_http_client::SemaphoredClient;
+
+/// `mode` is the kind of ranking, e.g. `daily`, and `content` the type of works, e.g. `manga`. Latest ranking if no date (`YYYYMMDD`) is given
+pub(crate) async fn get(
+    client: SemaphoredClient,
+    mode: &str,
+    content: &str,
+    date: Option<&str>,
+    page: usize,
+) -> Result<Body, ApiError> {
+    let date = match date {
+        Some(d) => format!("&date={}", encode(d)),
+        None => String::new(),
+    };
+    query_plain(
+        client,
+        &format!(
+            "/ranking.php?mode={}&content={}{}&p={}&format=json",
+            encode(mode),
+            encode(content),
+            date,
+            page
+        ),
+    )
+    .await
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Body {
+    pub contents: Vec<Entry>,
+    pub rank_total: usize,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub illust_id: u64,
+    pub rank: usize,
+}
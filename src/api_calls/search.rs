@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+use super::{de_id, encode, ApiError, Root};
+use crate::gen_http_client::SemaphoredClient;
+
+/// `s_mode` is how the word is matched, e.g. `s_tag`, and `mode` which works are included, e.g. `safe`
+pub(crate) async fn get(
+    client: SemaphoredClient,
+    word: &str,
+    s_mode: &str,
+    mode: &str,
+    page: usize,
+) -> Result<Body, ApiError> {
+    let word = encode(word);
+    Root::query(
+        client,
+        &format!(
+            "/ajax/search/artworks/{}?word={}&order=date_d&mode={}&p={}&s_mode={}&type=all",
+            word,
+            word,
+            encode(mode),
+            page,
+            encode(s_mode),
+        ),
+    )
+    .await
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Body {
+    pub illust_manga: Results,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Results {
+    pub data: Vec<Work>,
+    pub total: usize,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Work {
+    /// Missing for ads
+    #[serde(default, deserialize_with = "de_id")]
+    pub id: u64,
+    #[serde(default)]
+    pub is_ad_container: bool,
+}
@@ -3,12 +3,12 @@ use serde::{Deserialize, Serialize};
 use super::{de_id, ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn get(client: SemaphoredClient, series_id: u64, page: usize) -> Result<Body, ApiError> {
-    Root::query(
-        client,
-        &format!("https://www.pixiv.net/ajax/series/{}?p={}", series_id, page,),
-    )
-    .await
+pub(crate) async fn get(
+    client: SemaphoredClient,
+    series_id: u64,
+    page: usize,
+) -> Result<Body, ApiError> {
+    Root::query(client, &format!("/ajax/series/{}?p={}", series_id, page,)).await
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -24,6 +24,11 @@ pub struct SeriesInfo {
     #[serde(deserialize_with = "de_id")]
     pub id: u64,
     pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// Cover image
+    #[serde(default)]
+    pub url: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
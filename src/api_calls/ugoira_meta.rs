@@ -3,15 +3,8 @@ use serde::{Deserialize, Serialize};
 use super::{ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn _get(client: SemaphoredClient, illust_id: u64) -> Result<Body, ApiError> {
-    Root::query(
-        client,
-        &format!(
-            "https://www.pixiv.net/ajax/illust/{}/ugoira_meta",
-            illust_id,
-        ),
-    )
-    .await
+pub(crate) async fn get(client: SemaphoredClient, illust_id: u64) -> Result<Body, ApiError> {
+    Root::query(client, &format!("/ajax/illust/{}/ugoira_meta", illust_id,)).await
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use super::{de_id, ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn get(
+pub(crate) async fn get(
     client: SemaphoredClient,
     user_id: u64,
     offset: usize,
@@ -13,7 +13,7 @@ pub async fn get(
     Root::query(
         client,
         &format!(
-            "https://www.pixiv.net/ajax/user/{}/illusts/bookmarks?tag=&offset={}&limit={}&rest={}",
+            "/ajax/user/{}/illusts/bookmarks?tag=&offset={}&limit={}&rest={}",
             user_id,
             offset,
             limit,
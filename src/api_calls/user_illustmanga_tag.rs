@@ -1,9 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use super::{de_id, ApiError, Root};
+use super::{de_id, encode, ApiError, Root};
 use crate::gen_http_client::SemaphoredClient;
 
-pub async fn get(
+pub(crate) async fn get(
     client: SemaphoredClient,
     user_id: u64,
     tag: &str,
@@ -13,8 +13,11 @@ pub async fn get(
     Root::query(
         client,
         &format!(
-            "https://www.pixiv.net/ajax/user/{}/illustmanga/tag?tag={}&offset={}&limit={}",
-            user_id, tag, offset, limit,
+            "/ajax/user/{}/illustmanga/tag?tag={}&offset={}&limit={}",
+            user_id,
+            encode(tag),
+            offset,
+            limit,
         ),
     )
     .await
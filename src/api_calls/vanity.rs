@@ -0,0 +1,9 @@
+use super::{fetch_url, ApiError};
+use crate::gen_http_client::SemaphoredClient;
+
+/// Follow a vanity link such as `pixiv.me/name`, and give the URL it leads to
+pub(crate) async fn get(client: SemaphoredClient, name: &str) -> Result<String, ApiError> {
+    let url = client.endpoints.vanity_url(name);
+    let resp = fetch_url(&client, &format!("/vanity/{}", name), &url).await?;
+    Ok(resp.url)
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File as StdFile, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    incremental::walk_illust_files,
+    lock::DirLock,
+    output::{emit, message, Event},
+    DownloadIllustModes, IndexSubcommands,
+};
+
+pub static ARCHIVE_FILE: &str = ".pixiv_archive";
+
+// ---------- On-disk representation
+
+/// One line of the archive file, describing a work that was downloaded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveEntry {
+    pub id: u64,
+    /// Number of pages of this work
+    pub pages: usize,
+    pub files: Vec<ArchivedFile>,
+    /// Unix timestamp of when this work was downloaded
+    pub downloaded_at: u64,
+    /// What download this work came from. Unknown if the entry was rebuilt from existing files
+    pub source: Option<DownloadIllustModes>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedFile {
+    /// Relative to the root of the archive
+    pub path: PathBuf,
+    pub size: u64,
+    /// SHA-256 of the contents, in hex
+    pub hash: String,
+}
+
+// ---------- Archive
+
+/// Append-only index of all works downloaded in a library. Later lines take precedence over earlier ones
+pub struct Archive {
+    root: PathBuf,
+    entries: Mutex<HashMap<u64, ArchiveEntry>>,
+    /// Whether an archive file was found when loading
+    existed: bool,
+    /// Last line was cut short, e.g. by a crash, so the next one must start on a line of its own
+    torn: AtomicBool,
+}
+
+impl Archive {
+    /// Load the archive located at the root of a library. If there is none, an empty one is returned
+    pub fn load(root: &Path) -> Result<Archive> {
+        let path = root.join(ARCHIVE_FILE);
+
+        let mut entries = HashMap::new();
+        let existed = path.is_file();
+        let mut torn = false;
+
+        if existed {
+            let mut file = StdFile::open(&path)?;
+            if file.seek(SeekFrom::End(0))? > 0 {
+                let mut last = [0];
+                file.seek(SeekFrom::End(-1))?;
+                file.read_exact(&mut last)?;
+                torn = last[0] != b'\n';
+            }
+            file.rewind()?;
+
+            for (i, line) in BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                // A run that didn't finish writing shouldn't prevent all others from working
+                match serde_json::from_str::<ArchiveEntry>(&line) {
+                    Ok(entry) => {
+                        entries.insert(entry.id, entry);
+                    }
+                    Err(e) => warn!(
+                        path = %path.display(),
+                        line = i + 1,
+                        error = %e,
+                        "ignoring damaged archive entry"
+                    ),
+                }
+            }
+        }
+
+        Ok(Archive {
+            root: root.to_path_buf(),
+            entries: Mutex::new(entries),
+            existed,
+            torn: AtomicBool::new(torn),
+        })
+    }
+
+    pub fn existed(&self) -> bool {
+        self.existed
+    }
+
+    pub fn into_entries(self) -> Vec<ArchiveEntry> {
+        self.entries.into_inner().unwrap().into_values().collect()
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, id: u64) -> bool {
+        self.entries.lock().unwrap().contains_key(&id)
+    }
+
+    /// Number of pages of a work, if it was recorded
+    pub fn page_count(&self, id: u64) -> Option<usize> {
+        self.entries.lock().unwrap().get(&id).map(|e| e.pages)
+    }
+
+    /// Whether a work was recorded and all of its files are still where they were
+    pub fn is_on_disk(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            Some(entry) => {
+                !entry.files.is_empty()
+                    && entry
+                        .files
+                        .iter()
+                        .all(|f| self.root.join(&f.path).is_file())
+            }
+            None => false,
+        }
+    }
+
+    /// Hash freshly downloaded files of a work and append it to the archive
+    pub fn record(
+        &self,
+        id: u64,
+        pages: usize,
+        paths: &[PathBuf],
+        source: Option<DownloadIllustModes>,
+    ) -> Result<()> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            files.push(self.archived_file(path)?);
+        }
+
+        self.append(ArchiveEntry {
+            id,
+            pages,
+            files,
+            downloaded_at: unix_now(),
+            source,
+        })
+    }
+
+    /// Hash again the files of a work after some were replaced, keeping where it came from
+    pub fn refresh(&self, id: u64, pages: usize, paths: &[PathBuf]) -> Result<()> {
+        let source = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|e| e.source.clone());
+        self.record(id, pages, paths, source)
+    }
+
+    fn archived_file(&self, path: &Path) -> Result<ArchivedFile> {
+        let (size, hash) = hash_file(path)?;
+
+        // Keep paths relative so that the library can be moved around
+        let path = match path.strip_prefix(&self.root) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => path.to_path_buf(),
+        };
+
+        Ok(ArchivedFile { path, size, hash })
+    }
+
+    fn append(&self, entry: ArchiveEntry) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(ARCHIVE_FILE))?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        if self.torn.swap(false, Ordering::Relaxed) {
+            line.insert(0, '\n');
+        }
+        file.write_all(line.as_bytes())?;
+
+        entries.insert(entry.id, entry);
+
+        Ok(())
+    }
+}
+
+// ---------- Utilities
+
+/// Returns the size and SHA-256 of a file
+pub fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut file = StdFile::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// ---------- Rebuilding
+
+pub async fn do_index_subcommand(s: IndexSubcommands) -> Result<()> {
+    match s {
+        IndexSubcommands::Rebuild { wait, directory } => {
+            let root = directory.unwrap_or_default();
+            let _lock = DirLock::acquire(&root, wait).await?;
+            let nb_works = rebuild_archive(if root == Path::new("") {
+                Path::new(".")
+            } else {
+                &root
+            })?;
+            emit(Event::Indexed { works: nb_works });
+            message!("Indexed {} works", nb_works);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the archive of a library with one made from the files currently in it
+fn rebuild_archive(root: &Path) -> Result<usize> {
+    // Group all found files by illust, ordered by page
+    let mut works: BTreeMap<u64, BTreeMap<usize, PathBuf>> = BTreeMap::new();
+    collect_works(root, &mut works)?;
+
+    let archive = Archive {
+        root: root.to_path_buf(),
+        entries: Mutex::new(HashMap::new()),
+        existed: false,
+        torn: AtomicBool::new(false),
+    };
+
+    // Start over from an empty file
+    StdFile::create(root.join(ARCHIVE_FILE))?;
+
+    for (id, pages) in &works {
+        let mut files = Vec::with_capacity(pages.len());
+        let mut downloaded_at = 0;
+        for path in pages.values() {
+            files.push(archive.archived_file(path)?);
+
+            // Best guess for time of download
+            if let Ok(modified) = path.metadata().and_then(|m| m.modified()) {
+                if let Ok(d) = modified.duration_since(UNIX_EPOCH) {
+                    downloaded_at = downloaded_at.max(d.as_secs());
+                }
+            }
+        }
+
+        archive.append(ArchiveEntry {
+            id: *id,
+            pages: pages.len(),
+            files,
+            downloaded_at,
+            source: None,
+        })?;
+    }
+
+    Ok(works.len())
+}
+
+fn collect_works(path: &Path, works: &mut BTreeMap<u64, BTreeMap<usize, PathBuf>>) -> Result<()> {
+    walk_illust_files(path, None, &mut |f| {
+        if f.temporary {
+            return;
+        }
+        let pages = works.entry(f.id).or_default();
+        // Files without a page number are put after the others
+        let page = f.page.unwrap_or(usize::MAX - pages.len());
+        pages.insert(page, f.path.to_path_buf());
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let tmp = tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        let entry = r#"{"id":1,"pages":1,"files":[],"downloaded_at":0,"source":null}"#;
+        write(
+            root.join(ARCHIVE_FILE),
+            format!("{}\n{{\"id\":2,\"pa", entry),
+        )
+        .unwrap();
+
+        let archive = Archive::load(&root).unwrap();
+        assert!(archive.contains(1));
+        assert!(!archive.contains(2));
+
+        // New entries don't get mixed up with the damaged one
+        archive.record(3, 0, &[], None).unwrap();
+        let reloaded = Archive::load(&root).unwrap();
+        assert!(reloaded.contains(1) && reloaded.contains(3));
+        assert!(read_to_string(root.join(ARCHIVE_FILE))
+            .unwrap()
+            .ends_with("}\n"));
+    }
+}
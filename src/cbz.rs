@@ -0,0 +1,103 @@
+use std::{
+    fs::{remove_dir, remove_file, rename, File as StdFile},
+    io::{copy, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    incremental::illust_id_from_filename,
+    metadata::{escape_xml, WorkMetadata},
+};
+
+/// Where a work stands in a series, for comic readers
+pub struct SeriesEntry {
+    pub title: String,
+    pub number: usize,
+}
+
+/// Pack the pages of a work into `<prefix><id>.cbz` in `dest_dir`, then remove them. Returns the path of the archive
+pub fn pack_cbz(
+    illust_id: u64,
+    name_prefix: &str,
+    dest_dir: &Path,
+    pages: &[PathBuf],
+    meta: &WorkMetadata,
+    series: Option<&SeriesEntry>,
+) -> Result<PathBuf> {
+    // Pages finish downloading in any order
+    let mut pages = pages.to_vec();
+    pages.sort_by_key(|p| {
+        p.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(illust_id_from_filename)
+            .and_then(|(_, page)| page)
+    });
+
+    let filename = format!("{}{}.cbz", name_prefix, illust_id);
+    let path = dest_dir.join(&filename);
+    let temp = dest_dir.join(format!("._{}", filename));
+
+    // Images are already compressed
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(StdFile::create(&temp)?);
+
+    // Readers sort entries by name
+    let width = pages.len().to_string().len().max(3);
+    for (i, page) in pages.iter().enumerate() {
+        let ext = page
+            .extension()
+            .and_then(|e| e.to_str())
+            .ok_or_else(|| anyhow!("No extension for `{}`", page.display()))?;
+        zip.start_file(format!("{:0width$}.{}", i + 1, ext), options)?;
+        copy(&mut StdFile::open(page)?, &mut zip)?;
+    }
+
+    zip.start_file("ComicInfo.xml", options)?;
+    zip.write_all(comic_info(meta, series, pages.len()).as_bytes())?;
+    zip.finish()?;
+
+    rename(&temp, &path)?;
+
+    // Only the archive is kept
+    for page in &pages {
+        remove_file(page)?;
+    }
+    // Pages may have been in a directory of their own
+    if let Some(dir) = pages.first().and_then(|p| p.parent()) {
+        if dir != dest_dir {
+            let _ = remove_dir(dir);
+        }
+    }
+
+    Ok(path)
+}
+
+/// Metadata read by Komga, Kavita and others, see https://anansi-project.github.io/docs/comicinfo/intro
+fn comic_info(meta: &WorkMetadata, series: Option<&SeriesEntry>, page_count: usize) -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<ComicInfo xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" ",
+        "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n"
+    ));
+
+    let mut field = |name: &str, value: &str| {
+        xml.push_str(&format!("  <{0}>{1}</{0}>\n", name, escape_xml(value)));
+    };
+
+    field("Title", &meta.title);
+    if let Some(series) = series {
+        field("Series", &series.title);
+        field("Number", &series.number.to_string());
+    }
+    field("Writer", &meta.creator);
+    field("Tags", &meta.tags.join(","));
+    field("Web", &meta.source_url);
+    field("PageCount", &page_count.to_string());
+    field("Manga", "Yes");
+
+    xml.push_str("</ComicInfo>\n");
+    xml
+}
@@ -0,0 +1,207 @@
+use std::{path::PathBuf, sync::Arc};
+
+use reqwest::{header::InvalidHeaderValue, ClientBuilder};
+use thiserror::Error;
+
+use crate::{
+    api_calls::{
+        illust::{self, IllustInfo},
+        illust_pages::{self, Page},
+        novel::{self, NovelInfo},
+        novel_series, ranking, search, series, ugoira_meta,
+        user_bookmarks::{self, Visibility},
+        user_illustmanga_tag, user_info, vanity, ApiError,
+    },
+    gen_http_client::{make_headers, Endpoints, Limits, NetworkSettings, SemaphoredClient},
+    recording::Recording,
+    transport::{ReqwestTransport, Transport},
+};
+
+/// Everything needed for making a client
+#[derive(Default)]
+pub struct ClientSettings {
+    /// Cookie of a logged-in session, for accessing restricted content
+    pub cookie: Option<String>,
+    pub limits: Limits,
+    pub network: NetworkSettings,
+    pub endpoints: Endpoints,
+    /// Save responses, or replay them instead of using network
+    pub recording: Option<Recording>,
+}
+
+/// Access to the pixiv API. Cheap to clone, clones share the same limits
+#[derive(Clone)]
+pub struct PixivClient {
+    http: SemaphoredClient,
+}
+
+impl PixivClient {
+    /// Make a client that uses the network
+    pub fn new(settings: ClientSettings) -> Result<PixivClient, ClientError> {
+        let headers =
+            make_headers(settings.cookie.as_deref()).map_err(ClientError::InvalidCookie)?;
+        let builder = ClientBuilder::new().default_headers(headers).gzip(true);
+        let client = settings
+            .network
+            .apply(builder)?
+            .build()
+            .map_err(ClientError::Http)?;
+
+        let mut client = PixivClient::with_transport(
+            Arc::new(ReqwestTransport { client }),
+            settings.limits,
+            settings.endpoints,
+            settings.recording,
+        );
+        client.http.logged_in = settings.cookie.is_some();
+
+        Ok(client)
+    }
+
+    /// Make a client that performs requests with something else than reqwest, e.g. a `MemoryTransport` in tests
+    pub fn with_transport(
+        transport: Arc<dyn Transport>,
+        limits: Limits,
+        endpoints: Endpoints,
+        recording: Option<Recording>,
+    ) -> PixivClient {
+        PixivClient {
+            http: SemaphoredClient::new(transport, limits, endpoints, recording),
+        }
+    }
+
+    /// Underlying client, for downloading images while respecting the same limits
+    pub fn http(&self) -> &SemaphoredClient {
+        &self.http
+    }
+
+    // ---------- Illusts
+
+    /// General information about an illust
+    pub async fn illust(&self, illust_id: u64) -> Result<IllustInfo, ApiError> {
+        illust::get(self.http.clone(), illust_id).await
+    }
+
+    /// All pages of an illust, with their image URLs
+    pub async fn illust_pages(&self, illust_id: u64) -> Result<Vec<Page>, ApiError> {
+        illust_pages::get(self.http.clone(), illust_id).await
+    }
+
+    /// Frames of an animated illust
+    pub async fn ugoira_meta(&self, illust_id: u64) -> Result<ugoira_meta::Body, ApiError> {
+        ugoira_meta::get(self.http.clone(), illust_id).await
+    }
+
+    /// One page of the illusts in a series, starting at 1
+    pub async fn series(&self, series_id: u64, page: usize) -> Result<series::Body, ApiError> {
+        series::get(self.http.clone(), series_id, page).await
+    }
+
+    /// One page of the results of a search, starting at 1. See `search::get` for parameters
+    pub async fn search_artworks(
+        &self,
+        word: &str,
+        s_mode: &str,
+        mode: &str,
+        page: usize,
+    ) -> Result<search::Body, ApiError> {
+        search::get(self.http.clone(), word, s_mode, mode, page).await
+    }
+
+    /// One page of a ranking, starting at 1. See `ranking::get` for parameters
+    pub async fn ranking(
+        &self,
+        mode: &str,
+        content: &str,
+        date: Option<&str>,
+        page: usize,
+    ) -> Result<ranking::Body, ApiError> {
+        ranking::get(self.http.clone(), mode, content, date, page).await
+    }
+
+    // ---------- Users
+
+    /// Where a vanity link such as `pixiv.me/name` leads, normally the page of a user
+    pub async fn vanity_destination(&self, name: &str) -> Result<String, ApiError> {
+        vanity::get(self.http.clone(), name).await
+    }
+
+    /// IDs of every work posted by a user
+    pub async fn user_profile(&self, user_id: u64) -> Result<user_info::Body, ApiError> {
+        user_info::get(self.http.clone(), user_id).await
+    }
+
+    /// Part of the illusts bookmarked by a user
+    pub async fn user_bookmarks(
+        &self,
+        user_id: u64,
+        offset: usize,
+        limit: usize,
+        visibility: Visibility,
+    ) -> Result<user_bookmarks::Body, ApiError> {
+        user_bookmarks::get(self.http.clone(), user_id, offset, limit, visibility).await
+    }
+
+    /// Part of the illusts and manga posted by a user with a tag
+    pub async fn user_works_with_tag(
+        &self,
+        user_id: u64,
+        tag: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<user_illustmanga_tag::Body, ApiError> {
+        user_illustmanga_tag::get(self.http.clone(), user_id, tag, offset, limit).await
+    }
+
+    // ---------- Novels
+
+    /// A novel, with its full text
+    pub async fn novel(&self, novel_id: u64) -> Result<NovelInfo, ApiError> {
+        novel::get(self.http.clone(), novel_id).await
+    }
+
+    /// General information about a series of novels
+    pub async fn novel_series(&self, series_id: u64) -> Result<novel_series::SeriesInfo, ApiError> {
+        novel_series::get(self.http.clone(), series_id).await
+    }
+
+    /// Part of the novels of a series, in order
+    pub async fn novel_series_contents(
+        &self,
+        series_id: u64,
+        last_order: usize,
+        limit: usize,
+    ) -> Result<novel_series::Contents, ApiError> {
+        novel_series::get_contents(self.http.clone(), series_id, last_order, limit).await
+    }
+}
+
+// -----
+
+/// Everything that can go wrong when making a client
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// Cookie contains characters that can't be sent in a header
+    #[error("cookie is not a valid header value")]
+    InvalidCookie(#[source] InvalidHeaderValue),
+    /// A CA bundle couldn't be read
+    #[error("couldn't read CA bundle `{}`", path.display())]
+    CaBundle {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A CA bundle doesn't contain valid PEM certificates
+    #[error("invalid certificates in `{}`", path.display())]
+    Certificate {
+        path: PathBuf,
+        #[source]
+        source: reqwest::Error,
+    },
+    /// A proxy URL couldn't be understood
+    #[error("invalid proxy `{0}`")]
+    InvalidProxy(String),
+    /// The underlying HTTP client couldn't be made
+    #[error("couldn't make http client")]
+    Http(#[source] reqwest::Error),
+}
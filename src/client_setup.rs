@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use pixiv_util::{
+    gen_http_client::{Endpoints, HostProxy, Limits, NetworkSettings, UrlRewrite},
+    recording::Recording,
+    ClientSettings, PixivClient,
+};
+
+use crate::{
+    config::Config,
+    user_mgmt::{retrieve_cookie, retrieve_network_settings},
+    ClientParameters, EndpointParameters, LimitParameters, NetworkParameters, RecordingParameters,
+};
+
+/// Retrieve everything needed for making a client for a user, along with their cookie
+pub async fn setup_client(
+    cookie_override: Option<String>,
+    user_override: Option<String>,
+    params: &ClientParameters,
+) -> Result<(PixivClient, Option<String>)> {
+    let config = Config::retrieve()?;
+
+    // Get a cookie, if any
+    let cookie = match &cookie_override {
+        Some(c) => Some(c.clone()),
+        None => retrieve_cookie(user_override.clone()).await?,
+    };
+
+    // Settings of the user override global ones, unless their cookie isn't being used
+    let network = if cookie_override.is_some() && user_override.is_none() {
+        config.network
+    } else {
+        config
+            .network
+            .merged_with(&retrieve_network_settings(user_override).await?)
+    };
+
+    // Make the HTTP client with correct headers, limits and proxies
+    let client = PixivClient::new(ClientSettings {
+        cookie: cookie.clone(),
+        limits: params.limits.applied_to(config.limits),
+        network,
+        endpoints: params.endpoints.applied_to(config.endpoints)?,
+        recording: params.recording.to_recording()?,
+    })?;
+
+    Ok((client, cookie))
+}
+
+// ---------- Command line values over configuration
+
+impl LimitParameters {
+    /// Replace values with those given on command line, if any
+    pub fn applied_to(&self, mut limits: Limits) -> Limits {
+        if let Some(m) = self.max_api_requests {
+            limits.max_api_requests = m;
+        }
+        if let Some(m) = self.max_image_downloads {
+            limits.max_image_downloads = m;
+        }
+        if let Some(r) = self.requests_per_second {
+            limits.requests_per_second = r;
+        }
+        limits
+    }
+}
+
+impl EndpointParameters {
+    /// Replace values with those given on command line, if any
+    pub fn applied_to(&self, mut endpoints: Endpoints) -> Result<Endpoints> {
+        if self.default_endpoints {
+            endpoints = Endpoints::default();
+        }
+        if let Some(o) = &self.api_origin {
+            endpoints.api_origin.clone_from(o);
+        }
+        if let Some(r) = &self.image_rewrite {
+            let Some((from, to)) = r.split_once('=') else {
+                return Err(anyhow!("Image rewrite `{}` is not FROM=TO", r));
+            };
+            endpoints.image_rewrite = Some(UrlRewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+        Ok(endpoints)
+    }
+}
+
+impl NetworkParameters {
+    /// Change settings with values given on command line
+    pub fn applied_to(self, network: NetworkSettings) -> Result<NetworkSettings> {
+        let base = if self.clear {
+            NetworkSettings::default()
+        } else {
+            network
+        };
+
+        let mut host_proxies = Vec::with_capacity(self.host_proxy.len());
+        for rule in self.host_proxy {
+            let Some((host, proxy)) = rule.split_once('=') else {
+                return Err(anyhow!("Host proxy rule `{}` is not HOST=PROXY", rule));
+            };
+            host_proxies.push(HostProxy {
+                host: host.to_string(),
+                proxy: if proxy == "direct" {
+                    None
+                } else {
+                    Some(proxy.to_string())
+                },
+            });
+        }
+
+        let ca_bundles = self
+            .ca_bundle
+            .into_iter()
+            .map(|p| p.canonicalize())
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(base.merged_with(&NetworkSettings {
+            proxy: self.proxy,
+            http_proxy: self.http_proxy,
+            https_proxy: self.https_proxy,
+            ca_bundles,
+            host_proxies,
+        }))
+    }
+}
+
+impl RecordingParameters {
+    pub fn to_recording(&self) -> Result<Option<Recording>> {
+        Ok(match (&self.record, &self.replay) {
+            (Some(d), None) => Some(Recording::record(d.clone(), self.record_images)?),
+            (None, Some(d)) => Some(Recording::replay(d.clone())?),
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use dirs::config_dir;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use pixiv_util::gen_http_client::{Endpoints, Limits, NetworkSettings};
+
+use crate::{
+    output::{is_json, print_json},
+    ConfigSubcommands, LinkMethod,
+};
+
+// ---------- File-related
+
+const CONFIG_FILE_NAME: &str = "pixiv_util_config.json";
+
+fn get_config_file_path() -> Result<PathBuf> {
+    let mut config = match config_dir() {
+        Some(c) => c,
+        _ => return Err(anyhow::anyhow!("No suitable configuration directory !")),
+    };
+
+    config.push(CONFIG_FILE_NAME);
+
+    Ok(config)
+}
+
+// ---------- Internal representation
+
+/// Settings shared by all commands
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Libraries that are looked into for pages that were already downloaded elsewhere
+    pub library_roots: Vec<PathBuf>,
+    /// How a page found in another library is brought into the current one
+    pub link_method: LinkMethod,
+    /// Concurrency and rate limits for HTTP requests
+    pub limits: Limits,
+    /// Proxies and certificates, for all users
+    pub network: NetworkSettings,
+    /// Where requests are sent
+    pub endpoints: Endpoints,
+}
+
+impl Config {
+    fn load_config(path: &Path) -> Result<Config> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let c = serde_json::from_reader(reader)?;
+        Ok(c)
+    }
+
+    fn save_config(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(writer, &self)?;
+        Ok(())
+    }
+
+    /// Load the configuration, or the defaults if there isn't any
+    pub fn retrieve() -> Result<Config> {
+        let path = get_config_file_path()?;
+        if !path.is_file() {
+            return Ok(Config::default());
+        }
+        Config::load_config(&path)
+    }
+}
+
+// ---------- High-level fns
+
+pub fn do_config_subcommand(s: ConfigSubcommands) -> Result<()> {
+    let path = get_config_file_path()?;
+
+    if let ConfigSubcommands::PrintPath = s {
+        if is_json() {
+            print_json(&json!({ "path": path }));
+        } else {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    let mut config = if path.is_file() {
+        Config::load_config(&path)?
+    } else {
+        Config::default()
+    };
+
+    match s {
+        ConfigSubcommands::Print => {
+            if is_json() {
+                print_json(&config);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&config)?);
+            }
+            return Ok(());
+        }
+        ConfigSubcommands::AddLibraryRoot { directory } => {
+            let directory = directory.canonicalize()?;
+            if !config.library_roots.contains(&directory) {
+                config.library_roots.push(directory);
+            }
+        }
+        ConfigSubcommands::RemoveLibraryRoot { directory } => {
+            let directory = directory.canonicalize().unwrap_or(directory);
+            let before = config.library_roots.len();
+            config.library_roots.retain(|r| r != &directory);
+            if config.library_roots.len() == before {
+                return Err(anyhow::anyhow!("No such library root in configuration !"));
+            }
+        }
+        ConfigSubcommands::SetLinkMethod { method } => config.link_method = method,
+        ConfigSubcommands::SetLimits(l) => config.limits = l.applied_to(config.limits),
+        ConfigSubcommands::SetNetwork(n) => config.network = n.applied_to(config.network)?,
+        ConfigSubcommands::SetEndpoints(e) => config.endpoints = e.applied_to(config.endpoints)?,
+        ConfigSubcommands::PrintPath => {}
+    }
+
+    config.save_config(&path)
+}
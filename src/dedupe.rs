@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    fs::{hard_link, remove_file, rename},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::{
+    archive::{hash_file, Archive},
+    config::Config,
+    incremental::{strip_order_prefix, walk_illust_files},
+    output::{emit, message, Event},
+    DedupeParameters, LinkMethod,
+};
+
+// ---------- During download
+
+/// Pages already present in other libraries, by file name. Pixiv gives every page a unique file name
+pub struct KnownPages {
+    by_name: HashMap<String, KnownPage>,
+    method: LinkMethod,
+    linked: AtomicUsize,
+    saved: AtomicU64,
+}
+
+struct KnownPage {
+    path: PathBuf,
+    size: u64,
+    hash: String,
+}
+
+impl KnownPages {
+    /// Gather all pages from the archives of the configured library roots
+    pub fn load(config: &Config) -> Result<KnownPages> {
+        let mut by_name = HashMap::new();
+
+        for root in &config.library_roots {
+            let archive = Archive::load(root)?;
+            if !archive.existed() {
+                message!(
+                    "Library `{}` has no index, it will be ignored. Use `index rebuild` to create one",
+                    root.display()
+                );
+                continue;
+            }
+
+            for entry in archive.into_entries() {
+                for file in entry.files {
+                    let Some(name) = file.path.file_name().and_then(|n| n.to_str()) else {
+                        continue;
+                    };
+                    // Pages may have been renamed after their position in a series
+                    by_name.insert(
+                        strip_order_prefix(name).to_string(),
+                        KnownPage {
+                            path: root.join(&file.path),
+                            size: file.size,
+                            hash: file.hash,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(KnownPages {
+            by_name,
+            method: config.link_method,
+            linked: AtomicUsize::new(0),
+            saved: AtomicU64::new(0),
+        })
+    }
+
+    /// If this page exists in another library and wasn't modified since, link it into `dest_dir`. Returns where the page now is
+    pub fn link_existing(&self, filename: &str, dest_dir: &Path) -> Result<Option<PathBuf>> {
+        let Some(known) = self.by_name.get(filename) else {
+            return Ok(None);
+        };
+
+        // Make sure the file is still the one that was recorded
+        match hash_file(&known.path) {
+            Ok((size, hash)) if size == known.size && hash == known.hash => {}
+            _ => return Ok(None),
+        }
+
+        let dest = dest_dir.join(filename);
+        if dest.exists() {
+            return Ok(None);
+        }
+
+        // e.g. hardlinks across filesystems, the page is then downloaded as usual
+        if let Err(e) = link(&known.path, &dest, self.method) {
+            warn!(from = %known.path.display(), error = %e, "couldn't link known page");
+            let _ = remove_file(&dest);
+            return Ok(None);
+        }
+        debug!(from = %known.path.display(), to = %dest.display(), "linked known page");
+
+        self.linked.fetch_add(1, Ordering::Relaxed);
+        self.saved.fetch_add(known.size, Ordering::Relaxed);
+
+        Ok(Some(dest))
+    }
+
+    pub fn print_summary(&self) {
+        let linked = self.linked.load(Ordering::Relaxed);
+        if linked != 0 {
+            message!(
+                "Linked {} pages from other libraries, saved {}",
+                linked,
+                human_size(self.saved.load(Ordering::Relaxed))
+            );
+        }
+    }
+}
+
+// ---------- As its own command
+
+pub fn do_dedupe_subcommand(params: DedupeParameters) -> Result<()> {
+    let config = Config::retrieve()?;
+
+    let roots = if params.roots.is_empty() {
+        config.library_roots
+    } else {
+        params.roots
+    };
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No library roots given or configured ! See `config add-library-root`"
+        ));
+    }
+    let method = params.link_method.unwrap_or(config.link_method);
+
+    // Hash every page of every library
+    let mut by_hash: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+    for root in &roots {
+        let mut paths = Vec::new();
+        walk_illust_files(root, None, &mut |f| {
+            if !f.temporary {
+                paths.push(f.path.to_path_buf());
+            }
+        })?;
+
+        for path in paths {
+            // Symbolic links are already deduplicated
+            if path.symlink_metadata()?.file_type().is_symlink() {
+                continue;
+            }
+            by_hash.entry(hash_file(&path)?).or_default().push(path);
+        }
+    }
+
+    // Replace all copies by links to the first one found
+    let mut nb_linked = 0;
+    let mut nb_failed = 0;
+    let mut saved = 0;
+    for ((size, _), paths) in by_hash {
+        let Some((original, copies)) = paths.split_first() else {
+            continue;
+        };
+        for copy in copies {
+            if is_same_file(original, copy)? {
+                continue;
+            }
+            if !params.dry_run {
+                if let Err(e) = replace_with_link(original, copy, method) {
+                    message!("{} -> {}: {:#}", copy.display(), original.display(), e);
+                    nb_failed += 1;
+                    continue;
+                }
+            }
+            message!("{} -> {}", copy.display(), original.display());
+            emit(Event::DuplicateLinked { copy, original });
+            nb_linked += 1;
+            saved += size;
+        }
+    }
+
+    emit(Event::Deduplicated {
+        linked: nb_linked,
+        failed: nb_failed,
+        bytes_saved: saved,
+        dry_run: params.dry_run,
+    });
+    message!(
+        "{} {} duplicate pages, {} saved",
+        if params.dry_run { "Found" } else { "Linked" },
+        nb_linked,
+        human_size(saved)
+    );
+    if nb_failed > 0 {
+        message!("Couldn't link {} pages", nb_failed);
+    }
+
+    Ok(())
+}
+
+// ---------- Linking
+
+fn link(original: &Path, dest: &Path, method: LinkMethod) -> io::Result<()> {
+    match method {
+        LinkMethod::Hardlink => hard_link(original, dest),
+        LinkMethod::Reflink => reflink_copy::reflink(original, dest),
+        LinkMethod::Symlink => symlink(&original.canonicalize()?, dest),
+    }
+}
+
+/// Atomically swap a file for a link to another one
+fn replace_with_link(original: &Path, copy: &Path, method: LinkMethod) -> Result<()> {
+    let Some(name) = copy.file_name().and_then(|n| n.to_str()) else {
+        return Err(anyhow::anyhow!("Invalid file name: {}", copy.display()));
+    };
+    let temp = copy.with_file_name(format!("._{}", name));
+
+    if let Err(e) = link(original, &temp, method) {
+        let _ = remove_file(&temp);
+        return Err(e.into());
+    }
+    rename(&temp, copy)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, dest)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, dest: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, dest)
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(a.canonicalize()? == b.canonicalize()?)
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -4,37 +4,69 @@ use std::{
 };
 
 use anyhow::{anyhow, Result};
-use reqwest::RequestBuilder;
+use reqwest::header::CONTENT_LENGTH;
 use tokio::{
     fs::{rename, File},
     io::AsyncWriteExt,
 };
 use tokio_stream::StreamExt;
+use tracing::{debug, warn};
 
-use crate::gen_http_client::SemaphoredClient;
+use pixiv_util::{transport::Transport, PixivClient};
+
+pub const MAX_RETRIES: usize = 3;
+pub const TIMEOUT: u64 = 120;
 
 /// Download a file to a dir with rate limiting, timeouts, retries and temporary filenames
 pub async fn safe_dl(
-    client: SemaphoredClient,
+    client: PixivClient,
     url: String,
     dest_dir: PathBuf,
     max_tries: usize,
     timeout_time: Duration,
-) -> Result<()> {
+) -> Result<PathBuf> {
+    let dest = dest_dir.join(filename_from_url(&url));
+    safe_dl_to(client, url, dest, max_tries, timeout_time).await
+}
+
+/// Same as `safe_dl`, to a given path. An existing file there is only replaced once the download is complete
+pub async fn safe_dl_to(
+    client: PixivClient,
+    url: String,
+    dest: PathBuf,
+    max_tries: usize,
+    timeout_time: Duration,
+) -> Result<PathBuf> {
+    let client = client.http();
+
     // Build all paths
-    let paths = MyPaths::from_url_dest_dir(&url, &dest_dir);
+    let paths = MyPaths::from_dest(dest);
+
+    // Serve from recording instead of network if replaying
+    if let Some(r) = &client.recording {
+        if r.is_replay() {
+            r.load_image(&url, &paths.temp).await?;
+            rename(paths.temp, &paths.dest).await?;
+            return Ok(paths.dest);
+        }
+    }
 
     // Acquire download permit
-    let permit = client.semaphore.acquire().await.unwrap(); // TODO: Handle unwrap better ?
+    let permit = client.image_permit().await;
 
     let mut tries = 0;
 
     loop {
-        // Build request
-        let req = client.client.get(&url).timeout(timeout_time);
+        client.throttle().await;
 
         // Perform actual download
-        let dl_result = dl_file_to_disk(&paths.temp, req).await;
+        let dl_result = dl_file_to_disk(
+            &paths.temp,
+            client.transport.as_ref(),
+            &client.endpoints.image_url(&url),
+            timeout_time,
+        )
+        .await;
 
         // Check for error
         let download_error = match dl_result {
@@ -43,6 +75,7 @@ pub async fn safe_dl(
         };
 
         tries += 1;
+        warn!(url, tries, error = %download_error, "download failed");
 
         if tries >= max_tries {
             return Err(anyhow!(
@@ -58,9 +91,14 @@ pub async fn safe_dl(
     drop(permit);
 
     // Rename from temporary filename to permanent one
-    rename(paths.temp, paths.dest).await?;
+    rename(paths.temp, &paths.dest).await?;
+    debug!(path = %paths.dest.display(), "saved");
 
-    Ok(())
+    if let Some(r) = &client.recording {
+        r.save_image(&url, &paths.dest).await?;
+    }
+
+    Ok(paths.dest)
 }
 
 struct MyPaths {
@@ -70,39 +108,60 @@ struct MyPaths {
 }
 
 impl MyPaths {
-    fn from_url_dest_dir(url: &str, dest_dir: &Path) -> MyPaths {
-        // Extract filename
-        let filename = {
-            match url.rsplit('/').next() {
-                Some(p) => p,
-                None => url,
-            }
-        };
-
-        // Append filename
-        let mut dest = dest_dir.to_path_buf();
-        let mut temp = dest.clone();
-        dest.push(filename);
-        temp.push(format!("._{}", filename));
+    fn from_dest(dest: PathBuf) -> MyPaths {
+        let filename = dest
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let temp = dest.with_file_name(format!("._{}", filename));
 
         MyPaths {
-            filename: filename.to_string(),
+            filename,
             dest,
             temp,
         }
     }
 }
 
+/// Extract filename
+pub fn filename_from_url(url: &str) -> &str {
+    match url.rsplit('/').next() {
+        Some(p) => p,
+        None => url,
+    }
+}
+
 /// Downloads a file from a URL to specified file path
-async fn dl_file_to_disk(save_path: &Path, req: RequestBuilder) -> Result<()> {
-    let resp = req.send().await?;
-    resp.error_for_status_ref()?;
+async fn dl_file_to_disk(
+    save_path: &Path,
+    transport: &dyn Transport,
+    url: &str,
+    timeout_time: Duration,
+) -> Result<()> {
+    let mut resp = transport.get_stream(url, timeout_time).await?;
+    if !resp.status.is_success() {
+        return Err(anyhow!("server returned {}", resp.status));
+    }
 
     let mut file = File::create(&save_path).await?;
-    let mut stream = resp.bytes_stream();
+    let mut written = 0;
 
-    while let Some(data) = stream.next().await {
-        file.write_all(&data?).await?
+    while let Some(data) = resp.body.next().await {
+        let data = data?;
+        file.write_all(&data).await?;
+        written += data.len() as u64;
+    }
+
+    // Connection may have been cut short
+    let expected = resp
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(expected) = expected {
+        if expected != written {
+            return Err(anyhow!("received {} bytes out of {}", written, expected));
+        }
     }
 
     Ok(())
@@ -0,0 +1,37 @@
+use std::{fs::write, path::Path};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use pixiv_util::sources::{CollectionInfo, WorkRef};
+
+pub static MANIFEST_FILE: &str = "series.json";
+
+/// Description of a series, kept in its directory
+#[derive(Serialize)]
+struct SeriesManifest<'a> {
+    title: &'a str,
+    description: &'a str,
+    cover_url: Option<&'a str>,
+    /// IDs of works in reading order
+    works: Vec<u64>,
+}
+
+/// Write the manifest of a series in a directory, replacing the previous one
+pub fn write_manifest(dir: &Path, info: &CollectionInfo, mut works: Vec<WorkRef>) -> Result<()> {
+    // Works arrive in order of pages, which may not be reading order. Those without a position go last
+    works.sort_by_key(|w| w.order.unwrap_or(usize::MAX));
+
+    let manifest = SeriesManifest {
+        title: &info.title,
+        description: &info.description,
+        cover_url: info.cover_url.as_deref(),
+        works: works.into_iter().map(|w| w.id).collect(),
+    };
+    write(
+        dir.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    Ok(())
+}
@@ -1,54 +1,200 @@
-mod individual;
-mod series;
+mod manifest;
 mod single;
-mod user_bookmarks;
-mod user_posts;
 
 use std::{
+    collections::{BTreeMap, HashSet},
     env::current_dir,
     fs::read_dir,
+    io::{stdin, stdout, IsTerminal, Write},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::{anyhow, Result};
+use futures::{future::join_all, StreamExt};
 use tokio::{
-    fs::create_dir,
-    spawn,
-    sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        oneshot::{self, Sender},
-    },
-    task::JoinSet,
+    fs::create_dir_all,
+    task::{spawn_blocking, JoinSet},
+};
+use tracing::{debug, error, error_span, info, warn, Instrument, Span};
+
+use pixiv_util::{
+    api_calls::user_bookmarks::Visibility,
+    sources::{self, paginate, Collection, WorkRef, WorkStream, DEFAULT_PREFETCH},
+    ApiError, PixivClient,
 };
 
 use crate::{
-    gen_http_client::SemaphoredClient,
-    incremental::{is_illust_in_files, list_all_files},
+    archive::Archive,
+    cbz::{pack_cbz, SeriesEntry},
+    config::Config,
+    dedupe::KnownPages,
+    incremental::{order_prefix, LocalWorks},
+    input_file::{merge_modes, read_input_file},
+    interrupt::Interrupt,
+    job::Job,
+    lock::DirLock,
+    metadata::{embed_metadata, WorkMetadata},
+    output::{emit, is_json, message, Event, SkipReason},
+    touch::{set_file_times, work_date},
     update_file::create_update_file,
     user_mgmt::get_user_id,
-    DirectoryPolicy, DownloadIllustModes, DownloadIllustParameters,
+    DirectoryPolicy, DownloadIllustModes, DownloadIllustParameters, FileDate, SearchRating,
+    TagMatch,
 };
-use individual::illusts_to_mpsc;
-use series::illusts_from_series;
+use manifest::write_manifest;
 use single::dl_one_illust;
-use user_bookmarks::illusts_from_user_bookmarks;
-use user_posts::{illusts_from_user_posts, illusts_from_user_posts_with_tag};
 
 // -----
 
 pub async fn download_illust(
     params: DownloadIllustParameters,
-    client: SemaphoredClient,
+    client: PixivClient,
     cookie: Option<String>,
+    wait: bool,
 ) -> Result<()> {
-    let mut internal_params = InternalDownloadParams::process_args(params, client, cookie)?;
-    internal_params.download_all().await?;
-    internal_params.create_update_file()?;
+    // If there is a specified path, use it, otherwise use blank for current dir
+    let base_dest = params.output_directory.clone().unwrap_or(current_dir()?);
+
+    // Only one run at a time in a directory
+    let _lock = DirLock::acquire(&base_dest, wait).await?;
+
+    // What to download may come from the command line and from a list
+    let mut modes: Vec<_> = params.mode.clone().into_iter().collect();
+    if let Some(path) = &params.input_file {
+        modes.extend(read_input_file(path, &client).await?);
+    }
+    if modes.is_empty() {
+        return Err(anyhow!("Nothing to download, give a mode or an input file"));
+    }
+    let modes = merge_modes(modes);
+
+    // Individual illusts may stand for their whole series
+    let mut expanded = Vec::new();
+    let series_illusts = modes.iter().position(|mode| {
+        matches!(
+            mode,
+            DownloadIllustModes::Individual {
+                whole_series: true,
+                ..
+            }
+        )
+    });
+    let modes = match series_illusts {
+        Some(index) => {
+            let mut modes = modes;
+            let DownloadIllustModes::Individual { illust_ids, .. } = modes.remove(index) else {
+                unreachable!()
+            };
+            expanded = expand_to_series(&client, &illust_ids).await?;
+            modes.extend(expanded.iter().cloned());
+            merge_modes(modes)
+        }
+        None => modes,
+    };
+
+    // Maybe continue where a previous download stopped. Modes before the interrupted one were finished
+    let (mut previous_job, first_mode) = match previous_job(&base_dest, &modes, params.resume)? {
+        Some((index, job)) => (Some(job), index),
+        None => (None, 0),
+    };
+    if first_mode > 0 {
+        message!(
+            "Skipping {} downloads finished before the interruption",
+            first_mode
+        );
+    }
+
+    let several = modes.len() > 1;
+    let interrupt = Interrupt::listen();
+    for mode in modes.into_iter().skip(first_mode) {
+        // Collections downloaded together are kept apart from each other, and series found from an illust are named
+        let force_named_dir = (several || expanded.contains(&mode))
+            && !params.disable_named_dir
+            && !matches!(mode, DownloadIllustModes::Individual { .. });
+        let mut internal_params = InternalDownloadParams::process_args(
+            params.clone(),
+            mode,
+            base_dest.clone(),
+            client.clone(),
+            cookie.clone(),
+            previous_job.take(),
+            interrupt.clone(),
+        )?;
+        internal_params.create_named_dir |= force_named_dir;
+        internal_params.wait = wait;
+
+        let finished = internal_params.download_all().await?;
+        if finished {
+            internal_params.create_update_file()?;
+        }
+
+        if let Some(k) = &internal_params.known_pages {
+            k.print_summary();
+        }
+
+        if !finished {
+            break;
+        }
+    }
 
     Ok(())
 }
 
+/// Replace illusts by the series they are part of, each series only once. Illusts not in a series, or that couldn't be looked up, are kept together
+async fn expand_to_series(
+    client: &PixivClient,
+    illust_ids: &[u64],
+) -> Result<Vec<DownloadIllustModes>> {
+    let infos = join_all(illust_ids.iter().map(|id| client.illust(*id))).await;
+
+    let mut modes = Vec::new();
+    let mut lone_ids = Vec::new();
+    let mut failed = Vec::new();
+    for (id, info) in illust_ids.iter().zip(infos) {
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                warn!(
+                    illust_id = id,
+                    error = format!("{:#}", e),
+                    "couldn't get series"
+                );
+                failed.push(id.to_string());
+                lone_ids.push(*id);
+                continue;
+            }
+        };
+        let Some(series) = info.series_nav_data else {
+            lone_ids.push(*id);
+            continue;
+        };
+        let mode = DownloadIllustModes::Series {
+            series_id: series.series_id,
+        };
+        if !modes.contains(&mode) {
+            message!("{} is part of series `{}`", id, series.title);
+            modes.push(mode);
+        }
+    }
+
+    if !failed.is_empty() {
+        message!(
+            "Couldn't tell the series of {}, downloading them on their own",
+            failed.join(", ")
+        );
+    }
+
+    if !lone_ids.is_empty() {
+        modes.push(DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids: lone_ids,
+        });
+    }
+
+    Ok(modes)
+}
+
 // -----
 
 // TODO: I don't really like that struct after all... Maybe a more linear way of doing things is better
@@ -57,10 +203,14 @@ struct InternalDownloadParams {
     /// Parameters for querying API and get illust ids
     source: DownloadSource,
 
-    client: SemaphoredClient,
+    client: PixivClient,
 
     /// Used for deduplication
-    file_list: Option<Arc<Vec<String>>>,
+    dedup: Option<Dedup>,
+    /// Index of the library the illusts are downloaded into
+    archive: Arc<Archive>,
+    /// Pages from other libraries that can be linked instead of downloaded
+    known_pages: Option<Arc<KnownPages>>,
 
     /// (1) Where all illusts will end up under
     base_dest: PathBuf,
@@ -74,96 +224,185 @@ struct InternalDownloadParams {
 
     /// After download is complete, create an update file ?
     make_update_file: bool,
+    /// Give downloaded files the date of their work
+    file_dates: Option<FileDate>,
+    /// Write work metadata into downloaded images
+    embed_metadata: bool,
+    /// Pack manga works into archives
+    cbz: bool,
+    /// Put position in series before file names
+    order_prefix: bool,
+
+    /// Interrupted download being continued
+    previous_job: Option<Job>,
+    interrupt: Interrupt,
+    /// Wait for other runs using the named directory instead of failing
+    wait: bool,
 }
 
 impl InternalDownloadParams {
     /// Will take arguments given by user from CLI, process them for download
     fn process_args(
         params: DownloadIllustParameters,
-        client: SemaphoredClient,
+        mode: DownloadIllustModes,
+        base_dest: PathBuf,
+        client: PixivClient,
         cookie: Option<String>,
+        previous_job: Option<Job>,
+        interrupt: Interrupt,
     ) -> Result<InternalDownloadParams> {
-        // If there is a specified path, use it, otherwise use blank for current dir
-        let base_dest = params.output_directory.clone().unwrap_or(current_dir()?);
+        // Works are recorded in the archive of the library they are downloaded to
+        let archive = Arc::new(Archive::load(&base_dest)?);
+
+        // If incremental is active, look in the archive of the library checked against, and in its files
+        let dedup = match &params.incremental {
+            Some(dir) => {
+                let library_root = dir.clone().unwrap_or_else(|| base_dest.clone());
+                let index = if library_root == base_dest {
+                    archive.clone()
+                } else {
+                    Arc::new(Archive::load(&library_root)?)
+                };
+                Some(Dedup {
+                    archive: index,
+                    local: Arc::new(LocalWorks::scan(
+                        &library_root,
+                        params.incremental_template.as_deref(),
+                    )?),
+                })
+            }
+            None => None,
+        };
 
-        // If incremental is active, list all files
-        let file_list = if let Some(o) = &params.incremental {
-            Some(Arc::new(list_all_files(o.as_ref().unwrap_or(&base_dest))?))
+        // Look for pages in other libraries
+        let known_pages = if params.dedupe {
+            Some(Arc::new(KnownPages::load(&Config::retrieve()?)?))
         } else {
             None
         };
 
         // Check if we're going to create a named sub directory
         let create_named_dir =
-            should_create_named_dir(params.disable_named_dir, &params.mode, &base_dest)?;
+            should_create_named_dir(params.disable_named_dir, &mode, &base_dest)?;
 
         // Should we create an update file
         let make_update_file = !params.no_update_file & params.incremental.is_none();
 
         // Process arguments
-        let mode = DownloadSource::from_args(params.mode, cookie)?;
+        let mode = DownloadSource::from_args(mode, cookie)?;
 
         Ok(InternalDownloadParams {
             source: mode,
             client,
-            file_list,
+            dedup,
+            archive,
+            known_pages,
             base_dest,
             create_named_dir,
             directory_policy: params.directory_policy,
             dest_dir: None,
             make_update_file,
+            file_dates: params.file_dates,
+            embed_metadata: params.embed_metadata,
+            cbz: params.cbz,
+            order_prefix: params.order_prefix,
+            previous_job,
+            interrupt,
+            wait: false,
         })
     }
 
-    /// Download all files
-    async fn download_all(&mut self) -> Result<()> {
-        // Channel for passing illust ids
-        let (illust_id_tx, illust_id_rx) = mpsc::unbounded_channel();
-
-        // Channel for propagating name of collection
-        let (name_tx, name_rx) = if self.create_named_dir {
-            let channel = oneshot::channel();
-            (Some(channel.0), Some(channel.1))
-        } else {
-            (None, None)
-        };
-
-        // Feed MPSCs with illust ids coming from set source
-        let feed_task = spawn(feed_mpsc_from_source(
-            self.source.clone(),
-            self.client.clone(),
-            illust_id_tx,
-            name_tx,
-        ));
-
-        // Get name of collection
-        let name = if let Some(rx) = name_rx {
-            rx.await?
-        } else {
-            None
-        };
+    /// Download all files. Returns false if interrupted
+    async fn download_all(&mut self) -> Result<bool> {
+        // Get illust ids from set source
+        let collection = self.source.open(&self.client).await?;
 
         // Get full dest dir, create dir if necessary
-        let dest_dir = if let Some(name) = name {
+        let info = collection.info;
+        let name = info
+            .as_ref()
+            .map(|i| i.title.clone())
+            .filter(|_| self.create_named_dir);
+        let dest_dir = if let Some(job) = &self.previous_job {
+            job.dest_dir.clone()
+        } else if let Some(name) = name {
             let new_path = self.base_dest.join(name);
-            create_dir(&new_path).await?;
+            // May be left from a previous download of the same collection
+            create_dir_all(&new_path).await?;
+            debug!(dir = %new_path.display(), "created named directory");
             new_path
         } else {
             self.base_dest.to_owned()
         };
         self.dest_dir = Some(dest_dir.clone());
 
-        // Download illusts coming from MPSC
-        dl_illusts_from_channel(
-            self.client.clone(),
-            self.directory_policy,
-            illust_id_rx,
-            dest_dir,
-            self.file_list.clone(),
+        // Named directory may also be used on its own, e.g. when updating it
+        let _named_dir_lock = if dest_dir != self.base_dest {
+            Some(DirLock::acquire(&dest_dir, self.wait).await?)
+        } else {
+            None
+        };
+
+        // Download illusts as they come
+        let context = IllustDlContext {
+            client: self.client.clone(),
+            directory_policy: self.directory_policy,
+            dest_dir: dest_dir.clone(),
+            dedup: self.dedup.clone(),
+            archive: self.archive.clone(),
+            known_pages: self.known_pages.clone(),
+            source: self.source.to_arg(),
+            file_dates: self.file_dates,
+            embed_metadata: self.embed_metadata,
+            cbz: self.cbz,
+            order_prefix: self.order_prefix,
+            series_title: info.as_ref().map(|i| i.title.clone()),
+        };
+        let previously_done = match &self.previous_job {
+            Some(job) => job.done.clone(),
+            None => vec![],
+        };
+        let completion = dl_illusts_from_stream(
+            context,
+            collection.works,
+            self.interrupt.clone(),
+            previously_done,
         )
         .await?;
-        feed_task.await??;
 
+        match completion {
+            Completion::Finished { works } => {
+                if let Some(info) = &info {
+                    write_manifest(&dest_dir, info, works)?;
+                    debug!(dir = %dest_dir.display(), "wrote series manifest");
+                }
+                if self.previous_job.is_some() {
+                    Job::remove(&self.base_dest)?;
+                }
+                Ok(true)
+            }
+            Completion::Interrupted { done } => {
+                self.save_job(dest_dir, done)?;
+                message!("Interrupted, run the same download again to continue");
+                Ok(false)
+            }
+            Completion::Failed { done, error } => {
+                self.save_job(dest_dir, done)?;
+                message!("Run the same download again to retry what failed");
+                Err(error)
+            }
+        }
+    }
+
+    /// Keep what was done, so that the download can be continued
+    fn save_job(&self, dest_dir: PathBuf, done: Vec<u64>) -> Result<()> {
+        let job = Job {
+            source: self.source.to_arg(),
+            dest_dir,
+            done,
+        };
+        job.save(&self.base_dest)?;
+        info!(done = job.done.len(), "saved interrupted download");
         Ok(())
     }
 
@@ -182,10 +421,75 @@ impl InternalDownloadParams {
 
         let dest_dir = self.dest_dir.clone().unwrap_or(self.base_dest.clone());
 
-        create_update_file(&dest_dir, &arg)
+        create_update_file(&dest_dir, &arg)?;
+        debug!(dir = %dest_dir.display(), "created update file");
+        Ok(())
     }
 }
 
+/// Find an interrupted download of one of the sources in the directory, and whether to continue it.
+/// Also gives the position of its source
+fn previous_job(
+    dir: &Path,
+    modes: &[DownloadIllustModes],
+    resume: bool,
+) -> Result<Option<(usize, Job)>> {
+    let Some(job) = Job::load(dir)? else {
+        if resume {
+            return Err(anyhow!(
+                "No interrupted download to resume in `{}` !",
+                dir.display()
+            ));
+        }
+        return Ok(None);
+    };
+
+    let Some(index) = modes.iter().position(|m| m == &job.source) else {
+        if resume {
+            return Err(anyhow!(
+                "The interrupted download in `{}` is of something else !",
+                dir.display()
+            ));
+        }
+        message!("Ignoring the interrupted download of something else in this directory");
+        return Ok(None);
+    };
+
+    if resume || ask_resume(&job)? {
+        return Ok(Some((index, job)));
+    }
+
+    // Starting over
+    Job::remove(dir)?;
+    Ok(None)
+}
+
+fn ask_resume(job: &Job) -> Result<bool> {
+    if !stdin().is_terminal() || is_json() {
+        message!("Starting over an interrupted download, use --resume to continue it instead");
+        return Ok(false);
+    }
+
+    print!(
+        "This download was interrupted after {} illusts, continue it ? [Y/n] ",
+        job.done.len()
+    );
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+
+    Ok(!answer.trim().eq_ignore_ascii_case("n"))
+}
+
+/// How to tell if an illust was already downloaded
+#[derive(Clone)]
+struct Dedup {
+    /// Archive of the library, for works whose files can't be recognized by name
+    archive: Arc<Archive>,
+    /// IDs found in the names of all files of the library, for works missing from the archive
+    local: Arc<LocalWorks>,
+}
+
 #[derive(Clone)]
 enum DownloadSource {
     Individual {
@@ -209,12 +513,22 @@ enum DownloadSource {
         public: bool,
         private: bool,
     },
+    Search {
+        tag: String,
+        matching: TagMatch,
+        rating: SearchRating,
+    },
+    Ranking {
+        mode: String,
+        content: Option<String>,
+        date: Option<String>,
+    },
 }
 
 impl DownloadSource {
     fn from_args(args: DownloadIllustModes, cookie: Option<String>) -> Result<DownloadSource> {
         Ok(match args {
-            DownloadIllustModes::Individual { illust_ids } => {
+            DownloadIllustModes::Individual { illust_ids, .. } => {
                 DownloadSource::Individual { illust_ids }
             }
             DownloadIllustModes::Series { series_id } => DownloadSource::Series { series_id },
@@ -250,6 +564,24 @@ impl DownloadSource {
                     return Err(anyhow::anyhow!("No user cookie available !"));
                 }
             }
+            DownloadIllustModes::Search {
+                matching,
+                rating,
+                tag,
+            } => DownloadSource::Search {
+                tag,
+                matching,
+                rating,
+            },
+            DownloadIllustModes::Ranking {
+                mode,
+                content,
+                date,
+            } => DownloadSource::Ranking {
+                mode,
+                content,
+                date,
+            },
         })
     }
 
@@ -257,6 +589,7 @@ impl DownloadSource {
     fn to_arg(&self) -> DownloadIllustModes {
         match self {
             DownloadSource::Individual { illust_ids } => DownloadIllustModes::Individual {
+                whole_series: false,
                 illust_ids: illust_ids.clone(),
             },
             DownloadSource::Series { series_id } => DownloadIllustModes::Series {
@@ -281,6 +614,91 @@ impl DownloadSource {
                 public: *public,
                 private: *private,
             },
+            DownloadSource::Search {
+                tag,
+                matching,
+                rating,
+            } => DownloadIllustModes::Search {
+                matching: *matching,
+                rating: *rating,
+                tag: tag.clone(),
+            },
+            DownloadSource::Ranking {
+                mode,
+                content,
+                date,
+            } => DownloadIllustModes::Ranking {
+                mode: mode.clone(),
+                content: content.clone(),
+                date: date.clone(),
+            },
+        }
+    }
+
+    /// Start getting illust ids from API
+    async fn open(&self, client: &PixivClient) -> Result<Collection, ApiError> {
+        let client = client.clone();
+        match self {
+            DownloadSource::Individual { illust_ids } => {
+                Ok(Collection::from_ids(illust_ids.clone()))
+            }
+            DownloadSource::Series { series_id } => {
+                let source = sources::Series {
+                    series_id: *series_id,
+                };
+                paginate(client, Arc::new(source), DEFAULT_PREFETCH).await
+            }
+            DownloadSource::UserPosts { user_id } => sources::user_posts(&client, *user_id).await,
+            DownloadSource::UserPostsTag { user_id, tag } => {
+                let source = sources::UserWorksWithTag {
+                    user_id: *user_id,
+                    tag: tag.clone(),
+                };
+                paginate(client, Arc::new(source), DEFAULT_PREFETCH).await
+            }
+            DownloadSource::UserBookmarks { user_id } => {
+                bookmarks(client, *user_id, Visibility::Public).await
+            }
+            DownloadSource::OwnBookmarks {
+                user_id,
+                public,
+                private,
+            } => {
+                let mut collection = Collection::from_ids(vec![]);
+                if *private {
+                    let c = bookmarks(client.clone(), *user_id, Visibility::Private).await?;
+                    collection = collection.merge(c);
+                }
+                if *public {
+                    let c = bookmarks(client.clone(), *user_id, Visibility::Public).await?;
+                    collection = collection.merge(c);
+                }
+                Ok(collection)
+            }
+            DownloadSource::Search {
+                tag,
+                matching,
+                rating,
+            } => {
+                let source = sources::TagSearch {
+                    word: tag.clone(),
+                    s_mode: matching.to_param().to_string(),
+                    mode: rating.to_param().to_string(),
+                };
+                paginate(client, Arc::new(source), DEFAULT_PREFETCH).await
+            }
+            DownloadSource::Ranking {
+                mode,
+                content,
+                date,
+            } => {
+                let source = sources::Ranking {
+                    mode: mode.clone(),
+                    content: content.clone().unwrap_or_else(|| "all".to_string()),
+                    date: date.clone(),
+                };
+                paginate(client, Arc::new(source), DEFAULT_PREFETCH).await
+            }
         }
     }
 
@@ -289,117 +707,317 @@ impl DownloadSource {
     }
 }
 
+async fn bookmarks(
+    client: PixivClient,
+    user_id: u64,
+    visibility: Visibility,
+) -> Result<Collection, ApiError> {
+    let source = sources::UserBookmarks {
+        user_id,
+        visibility,
+    };
+    paginate(client, Arc::new(source), DEFAULT_PREFETCH).await
+}
+
 // -----
 
-/// Get illust ids from API and feed them to MPSC for download
-async fn feed_mpsc_from_source(
-    source: DownloadSource,
-    client: SemaphoredClient,
-    illust_id_tx: UnboundedSender<u64>,
-    name_tx: Option<Sender<Option<String>>>,
-) -> Result<()> {
-    match source {
-        DownloadSource::Individual { illust_ids } => {
-            if let Some(tx) = name_tx {
-                tx.send(None)
-                    .map_err(|e| anyhow!("Oneshot channel failed: {:?}", e))?;
-            }
-            illusts_to_mpsc(&illust_ids, illust_id_tx).await?
-        }
-        DownloadSource::Series { series_id } => {
-            illusts_from_series(client, series_id, illust_id_tx, name_tx).await?
-        }
-        DownloadSource::UserPosts { user_id } => {
-            if let Some(tx) = name_tx {
-                tx.send(None)
-                    .map_err(|e| anyhow!("Oneshot channel failed: {:?}", e))?;
-            }
-            illusts_from_user_posts(client, user_id, illust_id_tx).await?
-        }
-        DownloadSource::UserPostsTag { user_id, tag } => {
-            if let Some(tx) = name_tx {
-                tx.send(None)
-                    .map_err(|e| anyhow!("Oneshot channel failed: {:?}", e))?;
-            }
-            illusts_from_user_posts_with_tag(client, user_id, &tag, illust_id_tx).await?
-        }
-        DownloadSource::UserBookmarks { user_id } => {
-            if let Some(tx) = name_tx {
-                tx.send(None)
-                    .map_err(|e| anyhow!("Oneshot channel failed: {:?}", e))?;
+/// Everything needed to download an illust, shared by all illusts of a download
+#[derive(Clone)]
+struct IllustDlContext {
+    client: PixivClient,
+    directory_policy: DirectoryPolicy,
+    dest_dir: PathBuf,
+    dedup: Option<Dedup>,
+    archive: Arc<Archive>,
+    known_pages: Option<Arc<KnownPages>>,
+    /// Recorded in archive
+    source: DownloadIllustModes,
+    file_dates: Option<FileDate>,
+    embed_metadata: bool,
+    cbz: bool,
+    order_prefix: bool,
+    /// Name of the collection, if it has one
+    series_title: Option<String>,
+}
+
+/// Illusts being downloaded at once. More would only wait on the HTTP limits
+const MAX_ILLUSTS_IN_PROGRESS: usize = 32;
+
+/// How a download ended
+enum Completion {
+    /// With all works of the source, including skipped ones
+    Finished { works: Vec<WorkRef> },
+    /// Stopped by the user, with all illusts downloaded so far
+    Interrupted { done: Vec<u64> },
+    /// Some illusts couldn't be downloaded, or the source couldn't be listed entirely. With all illusts downloaded anyway
+    Failed {
+        done: Vec<u64>,
+        error: anyhow::Error,
+    },
+}
+
+/// What became of the illusts of a download so far
+#[derive(Default)]
+struct Tally {
+    done: Vec<u64>,
+    downloaded: usize,
+    skipped: usize,
+    failed: usize,
+    /// Kept so that the exit code tells what went wrong
+    first_error: Option<anyhow::Error>,
+}
+
+impl Tally {
+    fn add(&mut self, illust_id: u64, r: Result<bool>) {
+        match r {
+            Ok(downloaded) => {
+                self.done.push(illust_id);
+                if downloaded {
+                    self.downloaded += 1;
+                } else {
+                    self.skipped += 1;
+                }
             }
-            illusts_from_user_bookmarks(client, user_id, illust_id_tx, true, false).await?
-        }
-        DownloadSource::OwnBookmarks {
-            user_id,
-            public,
-            private,
-        } => {
-            if let Some(tx) = name_tx {
-                tx.send(None)
-                    .map_err(|e| anyhow!("Oneshot channel failed: {:?}", e))?;
+            Err(e) => {
+                self.failed += 1;
+                self.first_error.get_or_insert(e);
             }
-            illusts_from_user_bookmarks(client, user_id, illust_id_tx, public, private).await?
         }
     }
-
-    Ok(())
 }
 
-/// Initiates illust downloads coming from MPSC channel
-async fn dl_illusts_from_channel(
-    client: SemaphoredClient,
-    directory_policy: DirectoryPolicy,
-    mut illust_rx: UnboundedReceiver<u64>,
-    dest_dir: PathBuf,
-    file_list: Option<Arc<Vec<String>>>,
-) -> Result<()> {
+/// Initiates illust downloads as they come from source, a few at a time. Illusts in `done` are skipped.
+/// A failure doesn't stop the others, illusts in progress are always let finish
+async fn dl_illusts_from_stream(
+    context: IllustDlContext,
+    mut works: WorkStream,
+    mut interrupt: Interrupt,
+    done: Vec<u64>,
+) -> Result<Completion> {
+    let skip: HashSet<u64> = done.iter().copied().collect();
+    let mut listed = Vec::new();
     let mut set = JoinSet::new();
+    let mut tally = Tally {
+        done,
+        ..Default::default()
+    };
+    let mut source_error = None;
 
-    // For all received illust ids
-    while let Some(illust_id) = illust_rx.recv().await {
-        if let Some(fl) = &file_list {
-            // If there is a file list, check duplicates before dl
-            set.spawn(check_dup_and_dl(
-                client.clone(),
-                illust_id,
-                dest_dir.clone(),
-                directory_policy,
-                fl.clone(),
-            ));
-        } else {
-            // Otherwise, directly perform download
-            set.spawn(dl_one_illust(
-                client.clone(),
-                illust_id,
-                dest_dir.clone(),
-                directory_policy,
-            ));
+    // For all received illust ids, until interrupted
+    loop {
+        let work = tokio::select! {
+            biased;
+            _ = interrupt.wait() => break,
+            w = works.next() => match w {
+                Some(Ok(w)) => w,
+                Some(Err(e)) => {
+                    error!(error = format!("{:#}", e), "listing failed");
+                    source_error = Some(e);
+                    break;
+                }
+                None => break,
+            },
+        };
+        listed.push(work);
+        emit(Event::WorkDiscovered { illust_id: work.id });
+        if skip.contains(&work.id) {
+            emit(Event::Skipped {
+                illust_id: work.id,
+                reason: SkipReason::Resumed,
+            });
+            tally.skipped += 1;
+            continue;
+        }
+
+        // Wait for a free slot, so that the source isn't read further than needed
+        while set.len() >= MAX_ILLUSTS_IN_PROGRESS {
+            if let Some(r) = set.join_next().await {
+                let (illust_id, r) = r?;
+                tally.add(illust_id, r);
+            }
         }
+
+        let context = context.clone();
+        // Kept at all levels, so that any message tells which work it is about
+        let span = error_span!("work", illust_id = work.id);
+        set.spawn(
+            async move {
+                let r = check_dup_and_dl(context, work).await;
+                if let Err(e) = &r {
+                    error!(error = format!("{:#}", e), "download failed");
+                    emit(Event::Failed {
+                        illust_id: work.id,
+                        error: format!("{:#}", e),
+                    });
+                }
+                (work.id, r)
+            }
+            .instrument(span),
+        );
     }
 
+    // Let illusts in progress finish, so that no temporary files are left
     while let Some(r) = set.join_next().await {
-        r??
+        let (illust_id, r) = r?;
+        tally.add(illust_id, r);
     }
 
-    Ok(())
+    emit(Event::Finished {
+        dest_dir: &context.dest_dir,
+        works: listed.len(),
+        downloaded: tally.downloaded,
+        skipped: tally.skipped,
+        failed: tally.failed,
+        interrupted: interrupt.is_set(),
+    });
+
+    let error = match (source_error, tally.first_error) {
+        (Some(e), _) => {
+            Some(anyhow::Error::new(e).context("couldn't list all illusts to download"))
+        }
+        (None, Some(e)) => {
+            Some(e.context(format!("{} illusts couldn't be downloaded", tally.failed)))
+        }
+        (None, None) => None,
+    };
+    Ok(match error {
+        Some(error) => Completion::Failed {
+            done: tally.done,
+            error,
+        },
+        None if interrupt.is_set() => Completion::Interrupted { done: tally.done },
+        None => Completion::Finished { works: listed },
+    })
 }
 
-/// Checks if an illust is already in destination path and only download if not found
-async fn check_dup_and_dl(
-    client: SemaphoredClient,
-    illust_id: u64,
-    dest_dir: PathBuf,
-    directory_policy: DirectoryPolicy,
-    file_list: Arc<Vec<String>>,
-) -> Result<()> {
-    // Check if file is already downloaded
-    if is_illust_in_files(&illust_id.to_string(), &file_list) {
-        return Ok(());
-    }
+/// Checks if an illust is already in destination path and only download if not found, then record it in archive. Returns false if skipped
+async fn check_dup_and_dl(context: IllustDlContext, work: WorkRef) -> Result<bool> {
+    let illust_id = work.id;
+    let skipped = |reason| {
+        info!(?reason, "skipped");
+        emit(Event::Skipped { illust_id, reason });
+        Ok(false)
+    };
+
+    // Check if file is already downloaded, and if only some pages are missing
+    let skip_pages = match &context.dedup {
+        // Files that were deleted since don't count
+        Some(dedup) if dedup.archive.is_on_disk(illust_id) => return skipped(SkipReason::Archived),
+        Some(dedup) => match dedup.local.get(illust_id) {
+            Some(work) if work.partial => work.pages.clone(),
+            Some(work) if work.whole => return skipped(SkipReason::OnDisk),
+            Some(work) => {
+                // Pages found may only be the first ones
+                let expected = match dedup.archive.page_count(illust_id) {
+                    Some(n) => n,
+                    None => context.client.illust_pages(illust_id).await?.len(),
+                };
+                if work.pages.len() >= expected {
+                    return skipped(SkipReason::OnDisk);
+                }
+                work.pages.clone()
+            }
+            None => BTreeMap::new(),
+        },
+        None => BTreeMap::new(),
+    };
+    // All pages are needed to make an archive
+    let skip_pages = if context.cbz {
+        BTreeMap::new()
+    } else {
+        skip_pages
+    };
+
+    let name_prefix = match work.order {
+        Some(order) if context.order_prefix => order_prefix(order),
+        _ => String::new(),
+    };
 
     // Proceed to download
-    dl_one_illust(client, illust_id, dest_dir, directory_policy).await
+    let mut downloaded = dl_one_illust(
+        context.client.clone(),
+        illust_id,
+        context.dest_dir.clone(),
+        context.directory_policy,
+        &skip_pages.keys().copied().collect(),
+        context.known_pages,
+        &name_prefix,
+    )
+    .await?;
+
+    // Work info is only needed for touching up new files
+    let wants_info = context.file_dates.is_some() || context.embed_metadata || context.cbz;
+    let info = if wants_info && !downloaded.paths.is_empty() {
+        Some(context.client.illust(illust_id).await?)
+    } else {
+        None
+    };
+    let date = match (context.file_dates, &info) {
+        (Some(which), Some(info)) => Some(work_date(info, which)?),
+        _ => None,
+    };
+    let metadata = info
+        .as_ref()
+        .map(|info| WorkMetadata::from_info(illust_id, info));
+    let pack = info.is_some_and(|i| i.is_manga()) && context.cbz;
+    let series = match (context.series_title, work.order) {
+        (Some(title), Some(number)) => Some(SeriesEntry { title, number }),
+        _ => None,
+    };
+    let embed = context.embed_metadata;
+
+    // Hashing is blocking work
+    let (archive, source) = (context.archive, context.source);
+    let span = Span::current();
+    spawn_blocking(move || {
+        let _span = span.enter();
+        if let Some(metadata) = metadata.as_ref().filter(|_| embed) {
+            // Rewriting a linked page would replace the link with a copy
+            for path in downloaded
+                .paths
+                .iter()
+                .filter(|p| !downloaded.linked.contains(p))
+            {
+                // Image is already saved, it is only missing its metadata
+                match embed_metadata(path, metadata) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        message!("{}: format not supported for metadata", path.display())
+                    }
+                    Err(e) => warn!(
+                        path = %path.display(),
+                        error = format!("{:#}", e),
+                        "couldn't write metadata"
+                    ),
+                }
+            }
+        }
+        if let Some(metadata) = metadata.as_ref().filter(|_| pack) {
+            let cbz = pack_cbz(
+                illust_id,
+                &name_prefix,
+                &context.dest_dir,
+                &downloaded.paths,
+                metadata,
+                series.as_ref(),
+            )?;
+            debug!(path = %cbz.display(), "packed into archive");
+            downloaded.paths = vec![cbz];
+        }
+        if let Some(date) = date {
+            set_file_times(&downloaded.paths, date)?;
+        }
+        // Pages kept from before are part of the work as much as new ones
+        let mut paths: Vec<_> = skip_pages.into_values().collect();
+        paths.extend(downloaded.paths);
+        if paths.is_empty() {
+            return Ok(());
+        }
+        archive.record(illust_id, downloaded.pages, &paths, Some(source))
+    })
+    .await??;
+
+    Ok(true)
 }
 
 /// Checks if it would be wise to create a new directory named after series or user within specified destination directory
@@ -453,3 +1071,94 @@ pub fn count_files_dirs(path: &Path) -> Result<(usize, usize)> {
 
     Ok((nb_files, nb_dirs))
 }
+
+// -----
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use reqwest::StatusCode;
+    use tempfile::tempdir;
+
+    use pixiv_util::{
+        gen_http_client::{Endpoints, Limits},
+        transport::MemoryTransport,
+    };
+
+    use super::*;
+
+    fn pages_json(illust_id: u64, nb_pages: usize) -> String {
+        let pages: Vec<String> = (0..nb_pages)
+            .map(|p| {
+                format!(
+                    r#"{{"urls":{{"thumb_mini":null,"small":"","regular":"","original":"https://i.pximg.net/img-original/img/{}_p{}.png"}},"width":1,"height":1}}"#,
+                    illust_id, p
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"error":false,"message":"","body":[{}]}}"#,
+            pages.join(",")
+        )
+    }
+
+    #[tokio::test]
+    async fn individual_illust_is_downloaded_and_archived() {
+        let mut transport = MemoryTransport::default();
+        transport.insert(
+            "https://www.pixiv.net/ajax/illust/123/pages",
+            StatusCode::OK,
+            pages_json(123, 2),
+        );
+        for p in 0..2 {
+            transport.insert(
+                &format!("https://i.pximg.net/img-original/img/123_p{}.png", p),
+                StatusCode::OK,
+                format!("page {}", p),
+            );
+        }
+        let client = PixivClient::with_transport(
+            Arc::new(transport),
+            Limits::default(),
+            Endpoints::default(),
+            None,
+        );
+
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        download_illust(
+            DownloadIllustParameters {
+                incremental: None,
+                incremental_template: None,
+                fast_incremental: false,
+                disable_named_dir: false,
+                dedupe: false,
+                no_update_file: false,
+                file_dates: None,
+                embed_metadata: false,
+                cbz: false,
+                order_prefix: false,
+                resume: false,
+                input_file: None,
+                output_directory: Some(dir.clone()),
+                directory_policy: DirectoryPolicy::NeverCreate,
+                mode: Some(DownloadIllustModes::Individual {
+                    whole_series: false,
+                    illust_ids: vec![123],
+                }),
+            },
+            client,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(read(dir.join("123_p0.png")).unwrap(), b"page 0");
+        assert_eq!(read(dir.join("123_p1.png")).unwrap(), b"page 1");
+        assert!(Archive::load(&dir).unwrap().contains(123));
+        assert!(!dir.join(crate::lock::LOCK_FILE).exists());
+    }
+}
@@ -1,20 +1,31 @@
-use std::{path::PathBuf, time::Duration};
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use tokio::{fs::create_dir_all, task::JoinSet};
+use tokio::{
+    fs::{create_dir_all, rename},
+    task::{spawn_blocking, JoinSet},
+};
+use tracing::{debug, Instrument};
 
-use crate::{download::file::safe_dl, gen_http_client::SemaphoredClient, DirectoryPolicy};
+use pixiv_util::PixivClient;
 
-const MAX_RETRIES: usize = 3;
-const TIMEOUT: u64 = 120;
+use crate::{
+    dedupe::KnownPages,
+    download::file::{filename_from_url, safe_dl, MAX_RETRIES, TIMEOUT},
+    output::{emit, Event},
+    DirectoryPolicy,
+};
 
 pub async fn dl_one_illust(
-    client: SemaphoredClient,
+    client: PixivClient,
     illust_id: u64,
     mut dest_dir: PathBuf,
     directory_policy: DirectoryPolicy,
-) -> Result<()> {
-    let pages = crate::api_calls::illust_pages::get(client.clone(), illust_id).await?;
+    skip_pages: &BTreeSet<usize>,
+    known_pages: Option<Arc<KnownPages>>,
+    name_prefix: &str,
+) -> Result<DownloadedIllust> {
+    let pages = client.illust_pages(illust_id).await?;
 
     let in_dir = match directory_policy {
         DirectoryPolicy::AlwaysCreate => true,
@@ -24,27 +35,101 @@ pub async fn dl_one_illust(
 
     // If multiple pages, put everything in dir
     if in_dir {
-        create_dir_all(illust_id.to_string()).await?;
-        dest_dir.push(illust_id.to_string());
+        dest_dir.push(format!("{}{}", name_prefix, illust_id));
+        create_dir_all(&dest_dir).await?;
+        debug!(dir = %dest_dir.display(), "created work directory");
     }
 
+    let nb_pages = pages.len();
+
     let mut set = JoinSet::new();
 
-    // Initiate all downloads
-    for page in pages.into_iter() {
-        set.spawn(safe_dl(
+    // Initiate all downloads, except for pages already on disk
+    for (page_index, page) in pages.into_iter().enumerate() {
+        if skip_pages.contains(&page_index) {
+            continue;
+        }
+        let page_dl = link_or_dl_page(
             client.clone(),
             page.urls.original,
             dest_dir.clone(),
-            MAX_RETRIES,
-            Duration::from_secs(TIMEOUT),
-        ));
+            known_pages.clone(),
+            name_prefix.to_string(),
+        );
+        set.spawn(
+            async move { Ok::<_, anyhow::Error>((page_index, page_dl.await?)) }.in_current_span(),
+        );
     }
 
     // Wait for completion of all downloads
+    let mut paths = Vec::with_capacity(nb_pages);
+    let mut linked = Vec::new();
     while let Some(r) = set.join_next().await {
-        r??
+        let (page, (path, is_link)) = r??;
+        emit(Event::PageSaved {
+            illust_id,
+            page,
+            path: &path,
+        });
+        if is_link {
+            linked.push(path.clone());
+        }
+        paths.push(path)
+    }
+
+    Ok(DownloadedIllust {
+        pages: nb_pages,
+        paths,
+        linked,
+    })
+}
+
+/// If page is already in another library, link to it, otherwise download it. Then add prefix to its name.
+/// Also tells if it was linked
+async fn link_or_dl_page(
+    client: PixivClient,
+    url: String,
+    dest_dir: PathBuf,
+    known_pages: Option<Arc<KnownPages>>,
+    name_prefix: String,
+) -> Result<(PathBuf, bool)> {
+    let filename = filename_from_url(&url).to_string();
+
+    let mut linked = None;
+    if let Some(known) = known_pages {
+        let (filename, dir) = (filename.clone(), dest_dir.clone());
+        // Checking the hash of the existing file is blocking work
+        linked = spawn_blocking(move || known.link_existing(&filename, &dir)).await??;
     }
 
-    Ok(())
+    let is_link = linked.is_some();
+    let path = match linked {
+        Some(p) => p,
+        None => {
+            safe_dl(
+                client,
+                url,
+                dest_dir.clone(),
+                MAX_RETRIES,
+                Duration::from_secs(TIMEOUT),
+            )
+            .await?
+        }
+    };
+
+    if name_prefix.is_empty() {
+        return Ok((path, is_link));
+    }
+    let prefixed = dest_dir.join(format!("{}{}", name_prefix, filename));
+    rename(&path, &prefixed).await?;
+    debug!(path = %prefixed.display(), "renamed in reading order");
+    Ok((prefixed, is_link))
+}
+
+/// What ended up on disk after downloading an illust
+pub struct DownloadedIllust {
+    pub pages: usize,
+    pub paths: Vec<PathBuf>,
+    /// Pages linked from another library, which are shared and must be left as they are
+    pub linked: Vec<PathBuf>,
 }
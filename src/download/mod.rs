@@ -1,4 +1,4 @@
-mod file;
+pub mod file;
 mod illust;
 mod novel;
 mod update;
@@ -6,26 +6,69 @@ mod update;
 use anyhow::Result;
 
 use crate::{
-    gen_http_client::{make_client, make_headers},
-    user_mgmt::retrieve_cookie,
-    DownloadMediaParameters, DownloadParameters,
+    client_setup::setup_client,
+    parsers::{resolve_vanity, UrlTarget},
+    DirectoryPolicy, DownloadIllustParameters, DownloadMediaParameters, DownloadParameters,
+    GetParameters,
 };
 
-use self::{illust::download_illust, novel::download_novel, update::download_updates};
+use self::{
+    illust::download_illust,
+    novel::{download_novel, download_novel_series, save_novel, save_novel_series},
+    update::download_updates,
+};
 
 pub async fn do_download_subcommand(params: DownloadParameters) -> Result<()> {
-    // Get a cookie, if any
-    let cookie = match params.cookie_override {
-        Some(c) => Some(c),
-        None => retrieve_cookie(params.user_override).await?,
-    };
-
-    // Make the HTTP client with correct headers
-    let client = make_client(make_headers(cookie.as_deref())?)?;
+    let (client, cookie) =
+        setup_client(params.cookie_override, params.user_override, &params.client).await?;
 
     match params.media_params {
-        DownloadMediaParameters::Illust(i) => download_illust(i, client, cookie).await,
+        DownloadMediaParameters::Illust(i) => download_illust(i, client, cookie, params.wait).await,
         DownloadMediaParameters::Novel(n) => download_novel(n, client).await,
-        DownloadMediaParameters::Update(u) => download_updates(u, client, cookie).await,
+        DownloadMediaParameters::NovelSeries(n) => download_novel_series(n, client).await,
+        DownloadMediaParameters::Update(u) => {
+            download_updates(u, client, cookie, params.wait).await
+        }
     }
 }
+
+/// Download with default settings whatever was found in a URL
+pub async fn do_get_subcommand(params: GetParameters) -> Result<()> {
+    let (client, cookie) =
+        setup_client(params.cookie_override, params.user_override, &params.client).await?;
+
+    let mode = match params.target {
+        UrlTarget::Illusts(mode) => mode,
+        // Only the client can follow the link
+        UrlTarget::Vanity(name) => resolve_vanity(&client, &name).await?,
+        UrlTarget::Novel(novel_id) => {
+            let destination_file = params
+                .output_directory
+                .unwrap_or_default()
+                .join(format!("{}.txt", novel_id));
+            return save_novel(&client, novel_id, &destination_file).await;
+        }
+        UrlTarget::NovelSeries(series_id) => {
+            return save_novel_series(&client, series_id, params.output_directory).await;
+        }
+    };
+
+    let illust_params = DownloadIllustParameters {
+        incremental: None,
+        incremental_template: None,
+        fast_incremental: false,
+        disable_named_dir: false,
+        dedupe: false,
+        no_update_file: false,
+        output_directory: params.output_directory,
+        directory_policy: DirectoryPolicy::NeverCreate,
+        file_dates: None,
+        embed_metadata: false,
+        cbz: false,
+        order_prefix: false,
+        resume: false,
+        input_file: None,
+        mode: Some(mode),
+    };
+    download_illust(illust_params, client, cookie, params.wait).await
+}
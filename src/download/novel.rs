@@ -1,16 +1,158 @@
-use anyhow::Result;
-use tokio::{fs::File, io::AsyncWriteExt};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{gen_http_client::SemaphoredClient, DownloadNovelParameters};
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use tokio::{
+    fs::{create_dir_all, File},
+    io::AsyncWriteExt,
+};
 
-pub async fn download_novel(
-    params: DownloadNovelParameters,
-    client: SemaphoredClient,
+use pixiv_util::{
+    sources::{paginate, NovelSeries, DEFAULT_PREFETCH},
+    PixivClient,
+};
+
+use crate::{
+    incremental::order_prefix,
+    input_file::read_novel_input_file,
+    output::{emit, message, Event},
+    parsers::UrlTarget,
+    DownloadNovelParameters, DownloadNovelSeriesParameters,
+};
+
+pub async fn download_novel(params: DownloadNovelParameters, client: PixivClient) -> Result<()> {
+    if let (Some(novel_id), Some(destination_file)) = (params.novel_id, params.destination_file) {
+        save_novel(&client, novel_id, &destination_file).await?;
+    }
+
+    match params.input_file {
+        Some(path) => {
+            let targets = read_novel_input_file(&path, UrlTarget::Novel)?;
+            download_novel_list(&client, targets, params.output_directory).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Download a series, and those listed in the input file
+pub async fn download_novel_series(
+    params: DownloadNovelSeriesParameters,
+    client: PixivClient,
 ) -> Result<()> {
-    let info = crate::api_calls::novel::get(client, params.novel_id).await?;
+    if let Some(series_id) = params.series_id {
+        save_novel_series(&client, series_id, params.output_directory.clone()).await?;
+    }
+
+    match params.input_file {
+        Some(path) => {
+            let targets = read_novel_input_file(&path, UrlTarget::NovelSeries)?;
+            download_novel_list(&client, targets, params.output_directory).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Download novels as `<id>.txt` and series into directories named after them. Keep going when one fails
+async fn download_novel_list(
+    client: &PixivClient,
+    targets: Vec<UrlTarget>,
+    output_directory: Option<PathBuf>,
+) -> Result<()> {
+    let output_directory = output_directory.unwrap_or_default();
+
+    let mut nb_failed = 0;
+    let mut first_error = None;
+    for target in targets {
+        let r = match target {
+            UrlTarget::Novel(novel_id) => {
+                let path = output_directory.join(format!("{}.txt", novel_id));
+                save_novel(client, novel_id, &path)
+                    .await
+                    .with_context(|| format!("couldn't download novel {}", novel_id))
+            }
+            UrlTarget::NovelSeries(series_id) => {
+                save_novel_series(client, series_id, Some(output_directory.clone()))
+                    .await
+                    .with_context(|| format!("couldn't download novel series {}", series_id))
+            }
+            // Input files only give novels and series
+            UrlTarget::Illusts(_) | UrlTarget::Vanity(_) => unreachable!(),
+        };
+        if let Err(e) = r {
+            message!("{:#}", e);
+            nb_failed += 1;
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e.context(format!(
+            "{} novels or series couldn't be downloaded",
+            nb_failed
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub async fn save_novel(client: &PixivClient, novel_id: u64, path: &Path) -> Result<()> {
+    let info = client.novel(novel_id).await?;
 
-    let mut file = File::create(params.destination_file).await?;
+    let mut file = File::create(path).await?;
     file.write_all(info.content.as_bytes()).await?;
 
+    emit(Event::NovelSaved { novel_id, path });
+
+    Ok(())
+}
+
+/// Download every novel of a series into a directory named after it, with file names in reading order
+pub async fn save_novel_series(
+    client: &PixivClient,
+    series_id: u64,
+    output_directory: Option<PathBuf>,
+) -> Result<()> {
+    let source = NovelSeries { series_id };
+    let collection = paginate(client.clone(), Arc::new(source), DEFAULT_PREFETCH).await?;
+
+    let mut dest_dir = output_directory.unwrap_or_default();
+    if let Some(info) = &collection.info {
+        dest_dir.push(&info.title);
+    }
+    create_dir_all(&dest_dir).await?;
+
+    let mut nb_novels = 0;
+    let mut novels = collection
+        .works
+        .map_err(anyhow::Error::from)
+        .map_ok(|work| {
+            let (client, dest_dir) = (client.clone(), dest_dir.clone());
+            async move {
+                let prefix = work.order.map(order_prefix).unwrap_or_default();
+                let path = dest_dir.join(format!("{}{}.txt", prefix, work.id));
+                save_novel(&client, work.id, &path).await
+            }
+        })
+        .try_buffer_unordered(DEFAULT_PREFETCH);
+    while novels.try_next().await?.is_some() {
+        nb_novels += 1;
+    }
+
+    message!(
+        "Downloaded {} novels to `{}`",
+        nb_novels,
+        dest_dir.display()
+    );
+    emit(Event::Finished {
+        dest_dir: &dest_dir,
+        works: nb_novels,
+        downloaded: nb_novels,
+        skipped: 0,
+        failed: 0,
+        interrupted: false,
+    });
+
     Ok(())
 }
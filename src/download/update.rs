@@ -1,9 +1,11 @@
-use std::fs::File as StdFile;
+use std::{env::current_dir, fs::File as StdFile, path::PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+
+use pixiv_util::PixivClient;
 
 use crate::{
-    gen_http_client::SemaphoredClient, update_file::UPDATE_FILE, DirectoryPolicy,
+    input_file::read_list, output::message, update_file::UPDATE_FILE, DirectoryPolicy,
     DownloadIllustModes, DownloadIllustParameters, DownloadUpdateParameters,
 };
 
@@ -11,15 +13,66 @@ use super::illust::download_illust;
 
 pub async fn download_updates(
     params: DownloadUpdateParameters,
-    client: SemaphoredClient,
+    client: PixivClient,
     cookie: Option<String>,
+    wait: bool,
 ) -> Result<()> {
     if params.recursive {
         unimplemented!()
     }
 
-    let mut update_file_path = params.directory.clone().unwrap_or_default();
-    update_file_path.push(UPDATE_FILE);
+    let mut directories = Vec::new();
+    if params.directory.is_some() || params.input_file.is_none() {
+        let directory = match params.directory {
+            Some(directory) => directory,
+            None => current_dir()?,
+        };
+        directories.push(directory);
+    }
+    if let Some(path) = &params.input_file {
+        for (_, line) in read_list(path)? {
+            let directory = PathBuf::from(line);
+            if !directories.contains(&directory) {
+                directories.push(directory);
+            }
+        }
+    }
+
+    // A single directory fails as is
+    if let [directory] = &directories[..] {
+        return update_directory(directory.clone(), client, cookie, wait).await;
+    }
+
+    let nb_directories = directories.len();
+    let mut nb_failed = 0;
+    let mut first_error = None;
+    for directory in directories {
+        let r = update_directory(directory.clone(), client.clone(), cookie.clone(), wait)
+            .await
+            .with_context(|| format!("couldn't update `{}`", directory.display()));
+        if let Err(e) = r {
+            message!("{:#}", e);
+            nb_failed += 1;
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e.context(format!(
+            "{} of {} directories couldn't be updated",
+            nb_failed, nb_directories
+        ))),
+        None => Ok(()),
+    }
+}
+
+async fn update_directory(
+    directory: PathBuf,
+    client: PixivClient,
+    cookie: Option<String>,
+    wait: bool,
+) -> Result<()> {
+    let update_file_path = directory.join(UPDATE_FILE);
 
     let update_file = StdFile::open(update_file_path)
         .map_err(|e| anyhow!("Failed to open `{}` file: {}", UPDATE_FILE, e))?;
@@ -29,15 +82,24 @@ pub async fn download_updates(
     download_illust(
         DownloadIllustParameters {
             incremental: Some(None),
+            incremental_template: None,
             fast_incremental: false,
             disable_named_dir: true,
+            dedupe: false,
+            file_dates: None,
+            embed_metadata: false,
+            cbz: false,
+            order_prefix: false,
+            resume: false,
+            input_file: None,
             no_update_file: true,
-            output_directory: params.directory,
+            output_directory: Some(directory),
             directory_policy: DirectoryPolicy::NeverCreate,
-            mode,
+            mode: Some(mode),
         },
         client,
         cookie,
+        wait,
     )
     .await
 }
@@ -1,19 +1,205 @@
-use std::sync::Arc;
+use std::{fs::read, path::PathBuf, sync::Arc, time::Duration};
 
 use reqwest::{
     header::{HeaderMap, HeaderValue, InvalidHeaderValue, COOKIE, REFERER, USER_AGENT},
-    Client, ClientBuilder,
+    Certificate, ClientBuilder, NoProxy, Proxy, Url,
 };
-use tokio::sync::Semaphore;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex, Semaphore, SemaphorePermit},
+    time::{sleep_until, Instant},
+};
+use tracing::trace;
+
+use crate::{client::ClientError, recording::Recording, transport::Transport};
 
 #[allow(clippy::declare_interior_mutable_const)]
 const MY_USER_AGENT: HeaderValue = HeaderValue::from_static("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/105.0.0.0 Safari/537.36");
 #[allow(clippy::declare_interior_mutable_const)]
 const MY_REFERER: HeaderValue = HeaderValue::from_static("https://www.pixiv.net/");
 
-/// Everywhere in this program, max HTTP requests that can run at once
-const MAX_CONCURRENT_REQUESTS: usize = 50;
+/// Lowest rate that can be set, about one request every 17 minutes. Anything slower is raised to it
+pub const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
+
+/// How many HTTP requests can run at once, and how often they can start
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    /// Max API requests that can run at once
+    pub max_api_requests: usize,
+    /// Max image downloads that can run at once
+    pub max_image_downloads: usize,
+    /// Max requests of any kind started per second. 0 for no limit
+    pub requests_per_second: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_api_requests: 10,
+            max_image_downloads: 40,
+            requests_per_second: 0.0,
+        }
+    }
+}
+
+/// Where requests are sent. Can be changed to point at a caching proxy or a local test server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Endpoints {
+    /// Scheme and host of the API, e.g. `https://www.pixiv.net`
+    pub api_origin: String,
+    /// Replace the start of image URLs
+    pub image_rewrite: Option<UrlRewrite>,
+    /// Scheme and host of vanity links to users, e.g. `https://pixiv.me`
+    pub vanity_origin: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UrlRewrite {
+    /// e.g. `https://i.pximg.net`
+    pub from: String,
+    /// e.g. `http://localhost:8080`
+    pub to: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            api_origin: "https://www.pixiv.net".to_string(),
+            image_rewrite: None,
+            vanity_origin: "https://pixiv.me".to_string(),
+        }
+    }
+}
+
+impl Endpoints {
+    pub fn api_url(&self, path: &str) -> String {
+        format!("{}{}", self.api_origin.trim_end_matches('/'), path)
+    }
+
+    pub fn vanity_url(&self, name: &str) -> String {
+        format!("{}/{}", self.vanity_origin.trim_end_matches('/'), name)
+    }
+
+    pub fn image_url(&self, url: &str) -> String {
+        if let Some(r) = &self.image_rewrite {
+            if let Some(rest) = url.strip_prefix(&r.from) {
+                return format!("{}{}", r.to, rest);
+            }
+        }
+        url.to_string()
+    }
+}
+
+/// Proxies and certificates used to reach pixiv
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct NetworkSettings {
+    /// Proxy for all requests, e.g. `socks5://127.0.0.1:1080`
+    pub proxy: Option<String>,
+    /// Proxy for plain HTTP requests only
+    pub http_proxy: Option<String>,
+    /// Proxy for HTTPS requests only
+    pub https_proxy: Option<String>,
+    /// PEM files with additional root certificates to trust, e.g. for a proxy doing TLS interception
+    pub ca_bundles: Vec<PathBuf>,
+    /// Rules for specific hosts and their sub-domains, checked before the proxies above
+    pub host_proxies: Vec<HostProxy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostProxy {
+    pub host: String,
+    /// No proxy means a direct connection
+    pub proxy: Option<String>,
+}
+
+impl NetworkSettings {
+    /// Values from `other` take precedence, lists are put together
+    pub fn merged_with(mut self, other: &NetworkSettings) -> NetworkSettings {
+        if other.proxy.is_some() {
+            self.proxy.clone_from(&other.proxy);
+            // Scheme-specific proxies are tried first, so ones left from self would win over it
+            self.http_proxy = None;
+            self.https_proxy = None;
+        }
+        if other.http_proxy.is_some() {
+            self.http_proxy.clone_from(&other.http_proxy);
+        }
+        if other.https_proxy.is_some() {
+            self.https_proxy.clone_from(&other.https_proxy);
+        }
+        self.ca_bundles.extend_from_slice(&other.ca_bundles);
+        // Host rules from other come first so that they win
+        let mut host_proxies = other.host_proxies.clone();
+        host_proxies.append(&mut self.host_proxies);
+        self.host_proxies = host_proxies;
+        self
+    }
+
+    pub(crate) fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, ClientError> {
+        for path in &self.ca_bundles {
+            let pem = read(path).map_err(|source| ClientError::CaBundle {
+                path: path.clone(),
+                source,
+            })?;
+            let certs =
+                Certificate::from_pem_bundle(&pem).map_err(|source| ClientError::Certificate {
+                    path: path.clone(),
+                    source,
+                })?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
 
+        // Hosts that must not go through the general proxies
+        let direct_hosts: Vec<&str> = self
+            .host_proxies
+            .iter()
+            .filter(|h| h.proxy.is_none())
+            .map(|h| h.host.as_str())
+            .collect();
+        let no_proxy = || NoProxy::from_string(&direct_hosts.join(","));
+
+        // Rules for specific hosts
+        let mut rules = Vec::new();
+        for h in &self.host_proxies {
+            if let Some(p) = &h.proxy {
+                let url = Url::parse(p).map_err(|_| ClientError::InvalidProxy(p.clone()))?;
+                rules.push((h.host.clone(), url));
+            }
+        }
+        if !rules.is_empty() {
+            builder = builder.proxy(Proxy::custom(move |url| {
+                let host = url.host_str()?;
+                rules
+                    .iter()
+                    .find(|(h, _)| host == h || host.ends_with(&format!(".{}", h)))
+                    .map(|(_, p)| p.clone())
+            }));
+        }
+
+        let invalid = |p: &String| ClientError::InvalidProxy(p.clone());
+        if let Some(p) = &self.http_proxy {
+            let proxy = Proxy::http(p).map_err(|_| invalid(p))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy()));
+        }
+        if let Some(p) = &self.https_proxy {
+            let proxy = Proxy::https(p).map_err(|_| invalid(p))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy()));
+        }
+        if let Some(p) = &self.proxy {
+            let proxy = Proxy::all(p).map_err(|_| invalid(p))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy()));
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Headers sent with every request, with the cookie of a user if any
 pub fn make_headers(
     user_cookie: Option<&str>,
 ) -> std::result::Result<HeaderMap, InvalidHeaderValue> {
@@ -27,22 +213,137 @@ pub fn make_headers(
     Ok(headers)
 }
 
-pub fn make_client(headers: HeaderMap) -> reqwest::Result<SemaphoredClient> {
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+// https://users.rust-lang.org/t/reqwest-http-client-fails-when-too-much-concurrency/55644/2
+
+/// Used for limiting concurrency of requests, i.e., not having 1000s of requests at once. Acquire permit from the right semaphore before using transport, then drop permit when done
+#[derive(Clone)]
+pub struct SemaphoredClient {
+    /// For requests to the API
+    pub api_semaphore: Arc<Semaphore>,
+    /// For image downloads, so that they don't have to wait for all API requests to be done
+    pub image_semaphore: Arc<Semaphore>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub endpoints: Arc<Endpoints>,
+    /// Save responses, or replay them instead of using network
+    pub recording: Option<Arc<Recording>>,
+    pub transport: Arc<dyn Transport>,
+    /// Whether requests are sent with the cookie of a user
+    pub logged_in: bool,
+}
+
+impl SemaphoredClient {
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        limits: Limits,
+        endpoints: Endpoints,
+        recording: Option<Recording>,
+    ) -> SemaphoredClient {
+        let rate_limiter = if limits.requests_per_second > 0.0 {
+            Some(Arc::new(RateLimiter::new(
+                limits.requests_per_second.max(MIN_REQUESTS_PER_SECOND),
+            )))
+        } else {
+            None
+        };
 
-    let client = ClientBuilder::new()
-        .default_headers(headers)
-        .gzip(true)
-        .build()?;
+        SemaphoredClient {
+            api_semaphore: Arc::new(Semaphore::new(limits.max_api_requests.max(1))),
+            image_semaphore: Arc::new(Semaphore::new(limits.max_image_downloads.max(1))),
+            rate_limiter,
+            endpoints: Arc::new(endpoints),
+            recording: recording.map(Arc::new),
+            transport,
+            logged_in: false,
+        }
+    }
+
+    pub async fn api_permit(&self) -> SemaphorePermit<'_> {
+        acquire(&self.api_semaphore, "api").await
+    }
+
+    pub async fn image_permit(&self) -> SemaphorePermit<'_> {
+        acquire(&self.image_semaphore, "image").await
+    }
 
-    Ok(SemaphoredClient { semaphore, client })
+    /// Wait until a new request is allowed to start. Call right before each request
+    pub async fn throttle(&self) {
+        if let Some(r) = &self.rate_limiter {
+            r.wait().await
+        }
+    }
 }
 
-// https://users.rust-lang.org/t/reqwest-http-client-fails-when-too-much-concurrency/55644/2
+/// Take a permit, noting how long it took if all were in use
+async fn acquire<'a>(semaphore: &'a Semaphore, kind: &str) -> SemaphorePermit<'a> {
+    if let Ok(permit) = semaphore.try_acquire() {
+        return permit;
+    }
+    let start = Instant::now();
+    let permit = semaphore.acquire().await.unwrap(); // TODO: Handle this unwrap properly ?
+    trace!(kind, waited = ?start.elapsed(), "waited for a free request slot");
+    permit
+}
 
-/// Used for limiting concurrency of requests, i.e., not having 1000s of requests at once. Acquire permit from semaphore before using client, then drop permit when done
-#[derive(Clone)]
-pub struct SemaphoredClient {
-    pub semaphore: Arc<Semaphore>,
-    pub client: Client,
+/// Spaces out the start of requests evenly
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / requests_per_second),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        // Lock is kept while sleeping so that everyone else waits in line
+        let mut next = self.next.lock().await;
+        let wait = next.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            trace!(?wait, "throttled");
+        }
+        sleep_until(*next).await;
+        *next = Instant::now().max(*next) + self.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_proxies() {
+        let proxy = |p: &str| Some(p.to_string());
+        let global = NetworkSettings {
+            http_proxy: proxy("http://global-http"),
+            https_proxy: proxy("http://global-https"),
+            ..Default::default()
+        };
+
+        // Catch-all proxy of a profile replaces all of the global ones
+        let merged = global.clone().merged_with(&NetworkSettings {
+            proxy: proxy("socks5://profile"),
+            ..Default::default()
+        });
+        assert_eq!(merged.proxy, proxy("socks5://profile"));
+        assert_eq!(merged.http_proxy, None);
+        assert_eq!(merged.https_proxy, None);
+
+        // Unless the profile also gives some for specific schemes
+        let merged = global.clone().merged_with(&NetworkSettings {
+            proxy: proxy("socks5://profile"),
+            https_proxy: proxy("http://profile-https"),
+            ..Default::default()
+        });
+        assert_eq!(merged.https_proxy, proxy("http://profile-https"));
+        assert_eq!(merged.http_proxy, None);
+
+        // Nothing set in the profile keeps everything
+        let merged = global.merged_with(&NetworkSettings::default());
+        assert_eq!(merged.http_proxy, proxy("http://global-http"));
+        assert_eq!(merged.https_proxy, proxy("http://global-https"));
+    }
 }
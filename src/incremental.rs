@@ -1,43 +1,335 @@
-use std::{fs::read_dir, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 
-// TODO: This is not very smart or efficient
+// ---------- Scanning
 
-pub fn list_all_files(path: &Path) -> Result<Vec<String>> {
+/// Illusts found on disk, by ID
+#[derive(Default)]
+pub struct LocalWorks {
+    works: HashMap<u64, LocalWork>,
+}
+
+#[derive(Default, Clone)]
+pub struct LocalWork {
+    /// Numbered pages found, and where
+    pub pages: BTreeMap<usize, PathBuf>,
+    /// Leftover temporary files or gaps between pages means the download did not complete
+    pub partial: bool,
+    /// A file stands for the whole work, e.g. a CBZ archive or an ugoira
+    pub whole: bool,
+}
+
+impl LocalWorks {
+    /// Go through all files under a path and find all illusts in there
+    pub fn scan(path: &Path, template: Option<&str>) -> Result<LocalWorks> {
+        let mut local = LocalWorks::default();
+
+        walk_illust_files(path, template, &mut |f| {
+            let work = local.works.entry(f.id).or_default();
+            // Temporary files are cut short, their pages must be downloaded again
+            if f.temporary {
+                work.partial = true;
+            } else if let Some(p) = f.page {
+                work.pages.insert(p, f.path.to_path_buf());
+            } else {
+                work.whole = true;
+            }
+        })?;
+
+        // Holes in the page numbers
+        for work in local.works.values_mut() {
+            if let Some(last) = work.pages.keys().last() {
+                work.partial |= *last + 1 != work.pages.len();
+            }
+        }
+
+        Ok(local)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&LocalWork> {
+        self.works.get(&id)
+    }
+}
+
+/// A file that belongs to an illust
+pub struct FoundFile<'a> {
+    pub id: u64,
+    pub page: Option<usize>,
+    /// Left behind by an interrupted download
+    pub temporary: bool,
+    pub path: &'a Path,
+}
+
+/// Call `f` for every file under `path` that could be linked to an illust
+pub fn walk_illust_files(
+    path: &Path,
+    template: Option<&str>,
+    f: &mut impl FnMut(FoundFile),
+) -> Result<()> {
     // If path is empty, treat it as current directory
-    list_all_files_inner(if path == Path::new("") {
-        Path::new(".")
+    walk_inner(
+        if path == Path::new("") {
+            Path::new(".")
+        } else {
+            path
+        },
+        template,
+        None,
+        f,
+    )
+}
+
+fn walk_inner(
+    path: &Path,
+    template: Option<&str>,
+    dir_id: Option<u64>,
+    f: &mut impl FnMut(FoundFile),
+) -> Result<()> {
+    let entries = read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+
+    // A directory named like a work only stands for it if it holds pages of that work, unlike e.g. a year
+    let dir_id = dir_id.filter(|id| {
+        entries.iter().any(|e| {
+            let name = e.file_name();
+            let name = name.to_str().unwrap_or_default();
+            file_id(strip_temporary(name).1, template).is_some_and(|(i, _)| i == *id)
+        })
+    });
+
+    for e in entries {
+        let name = e.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let entry_path = e.path();
+
+        if e.file_type()?.is_dir() {
+            // Directories named after a work contain its pages
+            walk_inner(
+                &entry_path,
+                template,
+                strip_order_prefix(name).parse().ok(),
+                f,
+            )?;
+            continue;
+        }
+
+        let (temporary, name) = strip_temporary(name);
+        let found = file_id(name, template).or_else(|| dir_id.map(|i| (i, None)));
+
+        if let Some((id, page)) = found {
+            f(FoundFile {
+                id,
+                page,
+                temporary,
+                path: &entry_path,
+            })
+        }
+    }
+
+    Ok(())
+}
+
+// ---------- File name patterns
+
+/// Tell apart files left by an interrupted download, and give their final name
+fn strip_temporary(name: &str) -> (bool, &str) {
+    match name.strip_prefix("._") {
+        Some(n) => (true, n),
+        None => (false, name),
+    }
+}
+
+/// Illust ID and page number from a file name, made by pixiv or after the template of the user
+fn file_id(name: &str, template: Option<&str>) -> Option<(u64, Option<usize>)> {
+    illust_id_from_filename(name).or_else(|| template.and_then(|t| match_template(t, name)))
+}
+
+/// Extract illust ID and page number from a file name made by pixiv, such as `1234_p0.png` or `1234_ugoira0.jpg`, or made by packing a work, such as `1234.cbz`. Position in a series may come first
+pub fn illust_id_from_filename(name: &str) -> Option<(u64, Option<usize>)> {
+    // Hidden files are not illusts
+    if name.starts_with('.') {
+        return None;
+    }
+    let name = strip_order_prefix(name);
+
+    let stem = match name.split_once('.') {
+        Some((s, _)) => s,
+        None => name,
+    };
+    // Whole work packed in an archive
+    if name.ends_with(".cbz") {
+        return Some((stem.parse().ok()?, None));
+    }
+
+    let (id_s, rest) = stem.split_once('_')?;
+    let id = id_s.parse().ok()?;
+
+    if let Some(page_s) = rest.strip_prefix('p') {
+        Some((id, Some(page_s.parse().ok()?)))
+    } else if rest.starts_with("ugoira") {
+        Some((id, None))
     } else {
-        path
-    })
+        None
+    }
 }
 
-fn list_all_files_inner(path: &Path) -> Result<Vec<String>> {
-    let mut paths = Vec::new();
+/// Put before names of files and directories of a work, so that they sort in reading order
+pub fn order_prefix(order: usize) -> String {
+    format!("{:03} - ", order)
+}
 
-    let iterator = read_dir(path)?;
-    for e in iterator {
-        let e = e?;
-        if e.file_type()?.is_file() {
-            paths.push(e.path().display().to_string());
+/// Remove what `order_prefix` added, if anything
+pub fn strip_order_prefix(name: &str) -> &str {
+    match name.split_once(" - ") {
+        Some((order, rest)) if !order.is_empty() && order.bytes().all(|b| b.is_ascii_digit()) => {
+            rest
+        }
+        _ => name,
+    }
+}
+
+/// Match a file name against a template such as `{id} - p{page}`. Extension is ignored
+fn match_template(template: &str, name: &str) -> Option<(u64, Option<usize>)> {
+    let mut rest = match name.rsplit_once('.') {
+        Some((s, _)) => s,
+        None => name,
+    };
+    let mut template = template;
+    let mut id = None;
+    let mut page = None;
+
+    while !template.is_empty() {
+        if let Some(t) = template.strip_prefix("{id}") {
+            let (digits, r) = split_digits(rest)?;
+            id = Some(digits.parse().ok()?);
+            (template, rest) = (t, r);
+        } else if let Some(t) = template.strip_prefix("{page}") {
+            let (digits, r) = split_digits(rest)?;
+            page = Some(digits.parse().ok()?);
+            (template, rest) = (t, r);
         } else {
-            paths.extend_from_slice(&list_all_files_inner(&e.path())?)
+            // Literal characters up to next placeholder must match exactly
+            let literal_len = template
+                .find('{')
+                .filter(|i| *i > 0)
+                .unwrap_or(template.len());
+            let literal = &template[..literal_len];
+            rest = rest.strip_prefix(literal)?;
+            template = &template[literal_len..];
         }
     }
 
-    Ok(paths)
+    if !rest.is_empty() {
+        return None;
+    }
+
+    Some((id?, page))
 }
 
-pub fn is_illust_in_files(id: &str, files: &[String]) -> bool {
-    let mut found = false;
+fn split_digits(s: &str) -> Option<(&str, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    Some(s.split_at(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn temporary_pages_are_missing() {
+        let tmp = tempdir().unwrap();
+        for name in ["1234_p0.png", "1234_p2.png", "._1234_p1.png"] {
+            write(tmp.path().join(name), b"page").unwrap();
+        }
+
+        let local = LocalWorks::scan(tmp.path(), None).unwrap();
+        let work = local.get(1234).unwrap();
+        assert!(work.partial && !work.whole);
+        assert_eq!(work.pages.keys().copied().collect::<Vec<_>>(), [0, 2]);
+    }
 
-    for name in files {
-        if name.contains(id) {
-            found = true;
-            break;
+    #[test]
+    fn numbered_directories() {
+        let tmp = tempdir().unwrap();
+        for (dir, name) in [
+            ("2023", "1234_p0.png"),
+            ("2023", "notes.txt"),
+            ("5678", "5678_ugoira0.jpg"),
+            ("5678", "frames.zip"),
+        ] {
+            create_dir_all(tmp.path().join(dir)).unwrap();
+            write(tmp.path().join(dir).join(name), b"file").unwrap();
+        }
+
+        let local = LocalWorks::scan(tmp.path(), None).unwrap();
+        // Only a directory holding its own pages is a work
+        assert!(local.get(2023).is_none());
+        assert!(!local.get(1234).unwrap().whole);
+        assert!(local.get(5678).unwrap().whole);
+    }
+
+    #[test]
+    fn ids_from_filenames() {
+        let cases = [
+            ("1234_p0.png", Some((1234, Some(0)))),
+            ("91234567_p0.jpg", Some((91234567, Some(0)))),
+            ("91234567_p12.jpg", Some((91234567, Some(12)))),
+            ("1234_ugoira0.jpg", Some((1234, None))),
+            ("1234.cbz", Some((1234, None))),
+            ("003 - 1234_p1.png", Some((1234, Some(1)))),
+            ("003 - 1234.cbz", Some((1234, None))),
+            ("1234.png", None),
+            ("1234_master1200.jpg", None),
+            ("._1234_p0.png", None),
+            ("series.json", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(illust_id_from_filename(name), expected, "{}", name);
         }
     }
 
-    found
+    #[test]
+    fn templates() {
+        let cases = [
+            ("{id} - p{page}", "1234 - p2.png", Some((1234, Some(2)))),
+            (
+                "{id} - p{page}",
+                "91234567 - p0.jpg",
+                Some((91234567, Some(0))),
+            ),
+            ("{id} - p{page}", "1234 - 2.png", None),
+            ("{id} - p{page}", "x1234 - p2.png", None),
+            ("{id} - p{page}", "1234 - p2 copy.png", None),
+            ("[{id}]", "[1234].zip", Some((1234, None))),
+            ("[{id}]", "[].zip", None),
+        ];
+        for (template, name, expected) in cases {
+            assert_eq!(match_template(template, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn order_prefixes() {
+        assert_eq!(
+            strip_order_prefix(&format!("{}1234", order_prefix(3))),
+            "1234"
+        );
+        assert_eq!(strip_order_prefix("012 - 1234_p0.png"), "1234_p0.png");
+        assert_eq!(strip_order_prefix("My series - 1234"), "My series - 1234");
+        assert_eq!(strip_order_prefix(" - 1234"), " - 1234");
+        assert_eq!(strip_order_prefix("1234_p0.png"), "1234_p0.png");
+    }
 }
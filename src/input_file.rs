@@ -0,0 +1,216 @@
+use std::{
+    collections::HashSet,
+    fs::File as StdFile,
+    io::{stdin, BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use pixiv_util::PixivClient;
+
+use crate::{
+    output::message,
+    parsers::{parse_illust_id, parse_pixiv_url, resolve_vanity, UrlTarget},
+    DownloadIllustModes,
+};
+
+/// Read the lines of a list from a file, or from standard input if path is `-`, with their number. Blank lines and lines starting with `#` are left out
+pub fn read_list(path: &Path) -> Result<Vec<(usize, String)>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(stdin().lock())
+    } else {
+        let file = StdFile::open(path)
+            .map_err(|e| anyhow!("Failed to open `{}`: {}", path.display(), e))?;
+        Box::new(BufReader::new(file))
+    };
+
+    let mut lines = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        lines.push((i + 1, line.to_string()));
+    }
+
+    Ok(lines)
+}
+
+/// Tell about a line of a list that couldn't be understood
+fn report_bad_line(path: &Path, line_nb: usize, e: anyhow::Error) {
+    message!("{}:{}: {}", path.display(), line_nb, e);
+}
+
+fn report_skipped(nb_bad: usize) {
+    if nb_bad > 0 {
+        message!("Skipped {} lines that couldn't be understood", nb_bad);
+    }
+}
+
+/// Read what to download from a file with one URL or illust ID per line. Bad lines are reported
+pub async fn read_input_file(
+    path: &Path,
+    client: &PixivClient,
+) -> Result<Vec<DownloadIllustModes>> {
+    let mut modes = Vec::new();
+    let mut nb_bad = 0;
+    for (line_nb, line) in read_list(path)? {
+        match parse_line(&line, client).await {
+            Ok(mode) => modes.push(mode),
+            Err(e) => {
+                report_bad_line(path, line_nb, e);
+                nb_bad += 1;
+            }
+        }
+    }
+    report_skipped(nb_bad);
+
+    Ok(modes)
+}
+
+async fn parse_line(line: &str, client: &PixivClient) -> Result<DownloadIllustModes> {
+    // Bare numbers are illusts
+    if let Ok(illust_id) = parse_illust_id(line) {
+        return Ok(DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids: vec![illust_id],
+        });
+    }
+
+    match parse_pixiv_url(line)? {
+        UrlTarget::Illusts(mode) => Ok(mode),
+        UrlTarget::Vanity(name) => resolve_vanity(client, &name).await,
+        UrlTarget::Novel(_) | UrlTarget::NovelSeries(_) => {
+            Err(anyhow!("novels can't be downloaded along with illusts"))
+        }
+    }
+}
+
+/// Read novels and novel series to download from a file with one URL or ID per line, without duplicates. Bare IDs are turned into targets by `bare_id`. Bad lines are reported
+pub fn read_novel_input_file(path: &Path, bare_id: fn(u64) -> UrlTarget) -> Result<Vec<UrlTarget>> {
+    let mut targets = Vec::new();
+    let mut nb_bad = 0;
+    for (line_nb, line) in read_list(path)? {
+        match parse_novel_line(&line, bare_id) {
+            Ok(target) => {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+            Err(e) => {
+                report_bad_line(path, line_nb, e);
+                nb_bad += 1;
+            }
+        }
+    }
+    report_skipped(nb_bad);
+
+    Ok(targets)
+}
+
+fn parse_novel_line(line: &str, bare_id: fn(u64) -> UrlTarget) -> Result<UrlTarget> {
+    if let Ok(id) = line.parse() {
+        return Ok(bare_id(id));
+    }
+
+    match parse_pixiv_url(line)? {
+        target @ (UrlTarget::Novel(_) | UrlTarget::NovelSeries(_)) => Ok(target),
+        UrlTarget::Illusts(_) | UrlTarget::Vanity(_) => {
+            Err(anyhow!("illusts can't be downloaded along with novels"))
+        }
+    }
+}
+
+/// Put individual illusts together, those standing for their whole series apart from the others, and drop duplicates, keeping the order in which they first appear
+pub fn merge_modes(modes: Vec<DownloadIllustModes>) -> Vec<DownloadIllustModes> {
+    let mut merged = Vec::new();
+    let mut illust_ids = Vec::new();
+    let mut series_illust_ids = Vec::new();
+    let mut seen_ids = HashSet::new();
+    let mut seen_series_ids = HashSet::new();
+
+    for mode in modes {
+        match mode {
+            DownloadIllustModes::Individual {
+                whole_series: false,
+                illust_ids: ids,
+            } => illust_ids.extend(ids.into_iter().filter(|i| seen_ids.insert(*i))),
+            DownloadIllustModes::Individual {
+                whole_series: true,
+                illust_ids: ids,
+            } => series_illust_ids.extend(ids.into_iter().filter(|i| seen_series_ids.insert(*i))),
+            mode => {
+                if !merged.contains(&mode) {
+                    merged.push(mode);
+                }
+            }
+        }
+    }
+
+    // Illusts downloaded with their series don't need to be downloaded on their own too
+    illust_ids.retain(|i| !seen_series_ids.contains(i));
+
+    if !illust_ids.is_empty() {
+        merged.push(DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids,
+        });
+    }
+    if !series_illust_ids.is_empty() {
+        merged.push(DownloadIllustModes::Individual {
+            whole_series: true,
+            illust_ids: series_illust_ids,
+        });
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let individual = |whole_series, illust_ids| DownloadIllustModes::Individual {
+            whole_series,
+            illust_ids,
+        };
+        let series = |series_id| DownloadIllustModes::Series { series_id };
+
+        let merged = merge_modes(vec![
+            individual(false, vec![1, 2]),
+            series(7),
+            individual(true, vec![2, 3]),
+            series(8),
+            series(7),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                series(7),
+                series(8),
+                individual(false, vec![1]),
+                individual(true, vec![2, 3])
+            ]
+        );
+    }
+
+    #[test]
+    fn novel_lines() {
+        assert_eq!(
+            parse_novel_line("12", UrlTarget::NovelSeries).unwrap(),
+            UrlTarget::NovelSeries(12)
+        );
+        assert_eq!(
+            parse_novel_line(
+                "https://www.pixiv.net/novel/show.php?id=34",
+                UrlTarget::NovelSeries
+            )
+            .unwrap(),
+            UrlTarget::Novel(34)
+        );
+        assert!(parse_novel_line("https://www.pixiv.net/artworks/56", UrlTarget::Novel).is_err());
+    }
+}
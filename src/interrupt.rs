@@ -0,0 +1,56 @@
+use tokio::{signal, spawn, sync::watch};
+
+use crate::output::message;
+
+/// Tells whether the user asked to stop, with Ctrl-C or SIGTERM
+#[derive(Clone)]
+pub struct Interrupt {
+    rx: watch::Receiver<bool>,
+}
+
+impl Interrupt {
+    /// Start listening for signals. The first one sets the interrupt, a second one exits right away
+    pub fn listen() -> Interrupt {
+        let (tx, rx) = watch::channel(false);
+
+        spawn(async move {
+            if wait_signal().await.is_err() {
+                return;
+            }
+            message!("Stopping after the illusts in progress, press Ctrl-C again to abort");
+            let _ = tx.send(true);
+
+            if wait_signal().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+
+        Interrupt { rx }
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Returns once the interrupt is set
+    pub async fn wait(&mut self) {
+        // Sender is only dropped early if signals aren't available, in which case this never happens
+        if self.rx.wait_for(|s| *s).await.is_err() {
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_signal() -> std::io::Result<()> {
+    let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        r = signal::ctrl_c() => r,
+        _ = term.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() -> std::io::Result<()> {
+    signal::ctrl_c().await
+}
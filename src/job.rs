@@ -0,0 +1,50 @@
+use std::{
+    fs::{remove_file, File as StdFile},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::DownloadIllustModes;
+
+pub static JOB_FILE: &str = ".pixiv_job";
+
+/// State of an interrupted download, kept in the output directory until it is finished
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    pub source: DownloadIllustModes,
+    /// Where illusts were going, which may be a directory named after the collection
+    pub dest_dir: PathBuf,
+    /// Illusts that were completely downloaded. The source is listed again when resuming, so that works added since then are not missed
+    pub done: Vec<u64>,
+}
+
+impl Job {
+    /// Get the interrupted download of a directory, if any
+    pub fn load(dir: &Path) -> Result<Option<Job>> {
+        let path = dir.join(JOB_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let reader = BufReader::new(StdFile::open(path)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let writer = BufWriter::new(StdFile::create(dir.join(JOB_FILE))?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Forget the interrupted download of a directory, once it is finished
+    pub fn remove(dir: &Path) -> Result<()> {
+        let path = dir.join(JOB_FILE);
+        if path.is_file() {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+}
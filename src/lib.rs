@@ -0,0 +1,25 @@
+//! Client for the web API of pixiv, as used by the `pixiv_util` command line tool.
+//!
+//! Everything starts from a [`PixivClient`]:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use pixiv_util::{ClientSettings, PixivClient};
+//!
+//! let client = PixivClient::new(ClientSettings::default())?;
+//! for page in client.illust_pages(20).await? {
+//!     println!("{}", page.urls.original);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api_calls;
+pub mod client;
+pub mod gen_http_client;
+pub mod recording;
+pub mod sources;
+pub mod transport;
+
+pub use api_calls::ApiError;
+pub use client::{ClientError, ClientSettings, PixivClient};
@@ -0,0 +1,246 @@
+use std::{
+    fs::{hard_link, read_to_string, remove_file, rename, File as StdFile, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+use tracing::debug;
+
+use crate::output::message;
+
+pub static LOCK_FILE: &str = ".pixiv_lock";
+
+/// How often a held lock shows it is still alive
+const HEARTBEAT: Duration = Duration::from_secs(60);
+/// A lock that hasn't shown signs of life for this long is considered abandoned
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+/// How often to check again when waiting for a lock
+const RETRY_EVERY: Duration = Duration::from_secs(5);
+
+/// Contents of the lock file, for telling who holds it
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct LockInfo {
+    pid: u32,
+    /// Unix timestamp
+    started_at: u64,
+}
+
+/// Advisory lock on a directory, so that two runs don't work in it at the same time. Released when dropped
+pub struct DirLock {
+    path: PathBuf,
+    heartbeat: JoinHandle<()>,
+}
+
+impl DirLock {
+    /// Take the lock of a directory. If it is held by someone else, either fail or wait for it
+    pub async fn acquire(dir: &Path, wait: bool) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let mut announced = false;
+
+        // Current directory may be given as an empty path
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    debug!(path = %path.display(), "locked");
+                    return DirLock::hold(path, file);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            // Someone else has it
+            let holder = read_info(&path);
+            if is_stale(&path, holder.as_ref()) {
+                if remove_stale(&path, holder.as_ref())? {
+                    message!("Removed abandoned lock in `{}`", dir.display());
+                }
+                continue;
+            }
+
+            if !wait {
+                return Err(anyhow!(
+                    "`{}` is in use by another run ({}). Use --wait to wait for it to finish",
+                    dir.display(),
+                    describe_holder(&path)
+                ));
+            }
+
+            if !announced {
+                message!(
+                    "Waiting for another run to finish with `{}` ({})",
+                    dir.display(),
+                    describe_holder(&path)
+                );
+                announced = true;
+            }
+            sleep(RETRY_EVERY).await;
+        }
+    }
+
+    fn hold(path: PathBuf, mut file: StdFile) -> Result<DirLock> {
+        let info = LockInfo {
+            pid: std::process::id(),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        file.write_all(&serde_json::to_vec(&info)?)?;
+
+        // Keep modification time recent while the lock is held
+        let heartbeat = tokio::spawn(async move {
+            let mut ticks = interval(HEARTBEAT);
+            loop {
+                ticks.tick().await;
+                let _ = file.set_modified(SystemTime::now());
+            }
+        });
+
+        Ok(DirLock { path, heartbeat })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// A lock is stale if its process is gone, or if it hasn't been refreshed in a long time
+fn is_stale(path: &Path, info: Option<&LockInfo>) -> bool {
+    if let Some(info) = info {
+        if process_is_alive(info.pid) == Some(false) {
+            return true;
+        }
+    }
+
+    match path.metadata().and_then(|m| m.modified()) {
+        Ok(modified) => modified.elapsed().unwrap_or_default() > STALE_AFTER,
+        // Probably just released
+        Err(_) => false,
+    }
+}
+
+/// Remove a lock found to be stale, unless another run replaced it in the meantime.
+/// It is moved out of the way first, so that the lock checked is the one removed
+fn remove_stale(path: &Path, seen: Option<&LockInfo>) -> Result<bool> {
+    let moved = path.with_file_name(format!("{}.stale.{}", LOCK_FILE, std::process::id()));
+    match rename(path, &moved) {
+        Ok(()) => {}
+        // Another run got to it first
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+
+    let moved_info = read_info(&moved);
+    let removed = if moved_info.as_ref() == seen && is_stale(&moved, seen) {
+        true
+    } else {
+        // It was taken again since it was checked, put it back unless someone has taken the place
+        debug!(path = %path.display(), "lock was taken again, restoring it");
+        let _ = hard_link(&moved, path);
+        false
+    };
+    remove_file(&moved)?;
+
+    Ok(removed)
+}
+
+fn read_info(path: &Path) -> Option<LockInfo> {
+    serde_json::from_str(&read_to_string(path).ok()?).ok()
+}
+
+fn describe_holder(path: &Path) -> String {
+    match read_info(path) {
+        Some(info) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            format!(
+                "pid {}, started {} minutes ago",
+                info.pid,
+                now.saturating_sub(info.started_at) / 60
+            )
+        }
+        None => "unknown process".to_string(),
+    }
+}
+
+/// `None` when it can't be told on this platform
+#[cfg(target_os = "linux")]
+fn process_is_alive(pid: u32) -> Option<bool> {
+    Some(Path::new("/proc").join(pid.to_string()).exists())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_is_alive(_pid: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_lock(dir: &Path, pid: u32) -> LockInfo {
+        let info = LockInfo {
+            pid,
+            started_at: 1_700_000_000,
+        };
+        write(dir.join(LOCK_FILE), serde_json::to_vec(&info).unwrap()).unwrap();
+        info
+    }
+
+    #[tokio::test]
+    async fn stale_locks() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path = dir.join(LOCK_FILE);
+
+        // Held by a live process, recently refreshed
+        let info = write_lock(&dir, std::process::id());
+        assert!(!is_stale(&path, Some(&info)));
+        assert!(DirLock::acquire(&dir, false).await.is_err());
+
+        // Not refreshed in a long time
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - 2 * STALE_AFTER)
+            .unwrap();
+        assert!(is_stale(&path, Some(&info)));
+
+        // Replaced since it was found stale
+        let info = write_lock(&dir, std::process::id());
+        let old = LockInfo {
+            pid: info.pid,
+            started_at: 0,
+        };
+        assert!(!remove_stale(&path, Some(&old)).unwrap());
+        assert_eq!(read_info(&path), Some(info));
+
+        // Process is gone
+        if cfg!(target_os = "linux") {
+            let info = write_lock(&dir, u32::MAX);
+            assert!(is_stale(&path, Some(&info)));
+
+            let lock = DirLock::acquire(&dir, false).await.unwrap();
+            assert_eq!(read_info(&path).unwrap().pid, std::process::id());
+            drop(lock);
+            assert!(!path.exists());
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    fs::OpenOptions,
+    io::{stderr, IsTerminal},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter::Targets, fmt, prelude::*};
+
+/// Send logs of this program to stderr, or to a file. `verbose` is the number of `-v`
+pub fn init(verbose: u8, quiet: bool, log_file: Option<&Path>) -> Result<()> {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    // Dependencies are only worth hearing about when something is wrong
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(LevelFilter::WARN.min(level));
+
+    let layer = fmt::layer().with_target(false);
+    let layer = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("Failed to open log file `{}`: {}", path.display(), e))?;
+            layer.with_ansi(false).with_writer(Arc::new(file)).boxed()
+        }
+        None => layer
+            .with_ansi(stderr().is_terminal())
+            .with_writer(stderr)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .init();
+
+    Ok(())
+}
@@ -1,39 +1,88 @@
-mod api_calls;
+mod archive;
+mod cbz;
+mod client_setup;
+mod config;
+mod dedupe;
 mod download;
 mod find_not_bookmarked;
-mod gen_http_client;
 mod incremental;
+mod input_file;
+mod interrupt;
+mod job;
+mod lock;
+mod logging;
+mod metadata;
+mod output;
 mod parsers;
+mod touch;
 mod update_file;
 mod user_mgmt;
+mod verify;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, process::ExitCode};
 
 use anyhow::Result;
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 
-use download::do_download_subcommand;
+use archive::do_index_subcommand;
+use config::do_config_subcommand;
+use dedupe::do_dedupe_subcommand;
+use download::{do_download_subcommand, do_get_subcommand};
 use find_not_bookmarked::do_fnb_subcommand;
 use serde::{Deserialize, Serialize};
+use touch::do_touch_subcommand;
 use update_file::do_create_update_file_subcommand;
 use user_mgmt::do_users_subcommand;
+use verify::do_verify_subcommand;
 
 use parsers::*;
 
 // -----
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_long_help = output::EXIT_CODES)]
+struct Cli {
+    /// Print results as JSON for scripts: objects for users commands, one event per line for downloads, and errors
+    #[arg(long, global = true)]
+    json: bool,
+    /// Log more details of what is going on, up to `-vvv`
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Write logs to this file instead of stderr
+    #[arg(long, value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Args,
+}
+
+#[derive(Subcommand, Debug)]
 enum Args {
     /// Configure users for accessing restricted content
     #[command(subcommand)]
     Users(UsersSubcommands),
     /// Download something !
     Download(DownloadParameters),
+    /// Download whatever a pixiv URL points to: artwork, user, series, novel, search, ranking...
+    Get(GetParameters),
     /// Find all illusts on disk that haven't been bookmarked/liked
     FindNotBookmarked(FNBParameters),
     /// Creates an update file if necessary. One should be created automatically when downloading normally
     CreateUpdateFile(CreateUpdateFileParameters),
+    /// Manage the index of downloaded works kept at the root of a library
+    #[command(subcommand)]
+    Index(IndexSubcommands),
+    /// Check that all illusts in a directory are complete and undamaged
+    Verify(VerifyParameters),
+    /// Set the times of files on disk to the dates of their works
+    Touch(TouchParameters),
+    /// Replace pages present in several libraries by links to a single copy
+    Dedupe(DedupeParameters),
+    /// Change settings shared by all commands
+    #[command(subcommand)]
+    Config(ConfigSubcommands),
 }
 
 // -----
@@ -62,6 +111,12 @@ pub enum UsersSubcommands {
     ListUsers,
     /// Print the Pixiv ID of a user
     GetPixivID { username: String },
+    /// Set proxies and certificates used when downloading as this user. They take precedence over global ones
+    SetNetwork {
+        username: String,
+        #[command(flatten)]
+        network: NetworkParameters,
+    },
     /// Print the path of the database file
     PrintPath,
 }
@@ -76,6 +131,11 @@ pub struct DownloadParameters {
     /// Use a specific user for this download. If this isn't specified, the default user will be used.
     #[arg(short, long, value_name = "USER")]
     user_override: Option<String>,
+    #[command(flatten)]
+    client: ClientParameters,
+    /// If the destination directory is in use by another run, wait for it to finish instead of failing
+    #[arg(long)]
+    wait: bool,
     /// What kind of media we are downloading
     #[command(subcommand)]
     media_params: DownloadMediaParameters,
@@ -87,21 +147,29 @@ pub enum DownloadMediaParameters {
     Illust(DownloadIllustParameters),
     /// Download Novels
     Novel(DownloadNovelParameters),
+    /// Download all novels of a series
+    NovelSeries(DownloadNovelSeriesParameters),
     /// Check for new media and download automatically new posts
     Update(DownloadUpdateParameters),
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 pub struct DownloadIllustParameters {
-    /// Check if a directory already has some of the illusts that are about to be downloaded and if so, don't download them again. If option is specified but no path is given, will use same path as output
+    /// Check if a directory already has some of the illusts that are about to be downloaded and if so, don't download them again. Relies on the index of the library for works whose files are still there, and on file names. If option is specified but no path is given, will use same path as output
     #[arg(short, long, value_name = "DIR", require_equals = true)]
     incremental: Option<Option<PathBuf>>,
+    /// Also recognize illusts on disk with file names following this template, using `{id}` and `{page}` placeholders (e.g. `{id} - p{page}`)
+    #[arg(long, value_name = "TEMPLATE")]
+    incremental_template: Option<String>,
     /// When available, stop checking with server early as soon as an illust was found on drive. Use this option wisely
     #[arg(long)]
     fast_incremental: bool,
     /// Disable the creation of a new directory named after the series or user.
     #[arg(long)]
     disable_named_dir: bool,
+    /// Link pages already present in the configured library roots instead of downloading them again
+    #[arg(long)]
+    dedupe: bool,
     /// Do not create an update file for use with update functionality
     #[arg(long)]
     no_update_file: bool,
@@ -111,9 +179,27 @@ pub struct DownloadIllustParameters {
     /// Changes the directory creation behavior
     #[arg(short, long, value_enum, default_value_t = DirectoryPolicy::NeverCreate, value_name = "POLICY")]
     directory_policy: DirectoryPolicy,
-    /// What to download exactly
+    /// Set the modification time of downloaded files to this date of their work
+    #[arg(long, value_enum, value_name = "DATE")]
+    file_dates: Option<FileDate>,
+    /// Write title, artist, tags and source URL into downloaded images
+    #[arg(long)]
+    embed_metadata: bool,
+    /// Pack each manga work into a .cbz archive with a ComicInfo.xml, for comic readers
+    #[arg(long)]
+    cbz: bool,
+    /// Prefix names of files with the position of their work in the series, so that they sort in reading order
+    #[arg(long)]
+    order_prefix: bool,
+    /// Continue the interrupted download of the output directory without asking
+    #[arg(long)]
+    resume: bool,
+    /// Also download what is listed in this file, one URL or illust ID per line. Use `-` for standard input
+    #[arg(short = 'f', long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+    /// What to download exactly. May be omitted when using an input file
     #[command(subcommand)]
-    mode: DownloadIllustModes,
+    mode: Option<DownloadIllustModes>,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone)]
@@ -126,10 +212,14 @@ pub enum DirectoryPolicy {
     CreateIfMultiple,
 }
 
-#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DownloadIllustModes {
     /// Download a single illust
     Individual {
+        /// Download the whole series of each illust instead, if it is part of one
+        #[arg(long)]
+        #[serde(default)]
+        whole_series: bool,
         #[arg(value_parser = parse_illust_id)]
         illust_ids: Vec<u64>,
     },
@@ -148,7 +238,10 @@ pub enum DownloadIllustModes {
         user_id: u64,
     },
     /// Download all posts publicly liked/hearted/bookmarked by a user
-    UserBookmarks { user_id: u64 },
+    UserBookmarks {
+        #[arg(value_parser = parse_user_id)]
+        user_id: u64,
+    },
     /// Download all posts liked/hearted/bookmarked by the currently logged-in user
     OwnBookmarks {
         /// Download publicly bookmarked posts
@@ -158,6 +251,65 @@ pub enum DownloadIllustModes {
         #[arg(long)]
         private: bool,
     },
+    /// Download all illusts and manga found when searching for a tag
+    Search {
+        /// How the tag is matched
+        #[arg(long, value_enum, default_value_t = TagMatch::Partial)]
+        matching: TagMatch,
+        /// Which works are included
+        #[arg(long, value_enum, default_value_t = SearchRating::All)]
+        rating: SearchRating,
+        tag: String,
+    },
+    /// Download all illusts of a ranking
+    Ranking {
+        /// Kind of ranking, e.g. daily, weekly, monthly, rookie, original, male, female, daily_r18
+        #[arg(long, default_value = "daily")]
+        mode: String,
+        /// Only one type of works: illust, manga or ugoira
+        #[arg(long)]
+        content: Option<String>,
+        /// Day of the ranking, as YYYYMMDD. Latest one if omitted
+        #[arg(long)]
+        date: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum TagMatch {
+    /// Works with a tag containing the given one
+    Partial,
+    /// Works with exactly this tag
+    Exact,
+    /// Works with the tag in their title or caption
+    TitleCaption,
+}
+
+impl TagMatch {
+    pub const fn to_param(self) -> &'static str {
+        match self {
+            TagMatch::Partial => "s_tag",
+            TagMatch::Exact => "s_tag_full",
+            TagMatch::TitleCaption => "s_tc",
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SearchRating {
+    All,
+    Safe,
+    R18,
+}
+
+impl SearchRating {
+    pub const fn to_param(self) -> &'static str {
+        match self {
+            SearchRating::All => "all",
+            SearchRating::Safe => "safe",
+            SearchRating::R18 => "r18",
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -168,10 +320,30 @@ pub struct DownloadNovelParameters {
     /// Use a specific user for this download. If this isn't specified, the default user will be used.
     #[arg(short, long, value_name = "USER")]
     user_override: Option<String>,
-    /// ID of the novel to download
-    novel_id: u64,
+    /// ID of the novel to download. May be omitted when using an input file
+    #[arg(value_parser = parse_novel_id, required_unless_present = "input_file", requires = "destination_file")]
+    novel_id: Option<u64>,
     /// Where the text file will be
-    destination_file: PathBuf,
+    destination_file: Option<PathBuf>,
+    /// Also download what is listed in this file, one URL or novel ID per line. Use `-` for standard input
+    #[arg(short = 'f', long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+    /// Where novels of the input file are saved as `<id>.txt`, and directories of its series created. If not specified, will use working directory
+    #[arg(short, long)]
+    output_directory: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct DownloadNovelSeriesParameters {
+    /// ID of the series to download. May be omitted when using an input file
+    #[arg(value_parser = parse_series_id, required_unless_present = "input_file")]
+    series_id: Option<u64>,
+    /// Where a directory named after the series will be created. If not specified, will use working directory
+    #[arg(short, long)]
+    output_directory: Option<PathBuf>,
+    /// Also download what is listed in this file, one URL or series ID per line. Use `-` for standard input
+    #[arg(short = 'f', long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
@@ -179,8 +351,34 @@ pub struct DownloadUpdateParameters {
     /// When specified, go down all sub-directories and update everything
     #[arg(short, long)]
     recursive: bool,
-    /// Where we are updating. If omitted, uses current directory
+    /// Where we are updating. If omitted, uses current directory unless an input file is given
     directory: Option<PathBuf>,
+    /// Also update the directories listed in this file, one per line. Use `-` for standard input
+    #[arg(short = 'f', long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+}
+
+// -----
+
+#[derive(Parser, Debug)]
+pub struct GetParameters {
+    /// Directly specify a cookie for use over everything else
+    #[arg(short, long, value_name = "COOKIE", value_parser = sanitize_cookie)]
+    cookie_override: Option<String>,
+    /// Use a specific user for this download. If this isn't specified, the default user will be used.
+    #[arg(short, long, value_name = "USER")]
+    user_override: Option<String>,
+    #[command(flatten)]
+    client: ClientParameters,
+    /// If the destination directory is in use by another run, wait for it to finish instead of failing
+    #[arg(long)]
+    wait: bool,
+    /// Where the newly downloaded files will go. If not specified, will use working directory
+    #[arg(short, long)]
+    output_directory: Option<PathBuf>,
+    /// Address of a page on pixiv
+    #[arg(value_parser = parse_pixiv_url, value_name = "URL")]
+    target: UrlTarget,
 }
 
 // -----
@@ -211,14 +409,222 @@ pub struct CreateUpdateFileParameters {
 
 // -----
 
+#[derive(Subcommand, Debug)]
+pub enum IndexSubcommands {
+    /// Scan all files already present in a library and create its index from scratch
+    Rebuild {
+        /// If the library is in use by another run, wait for it to finish instead of failing
+        #[arg(long)]
+        wait: bool,
+        /// Root of the library. If omitted, uses current directory
+        directory: Option<PathBuf>,
+    },
+}
+
+// -----
+
+#[derive(Parser, Debug)]
+pub struct VerifyParameters {
+    /// Directly specify a cookie for use over everything else
+    #[arg(short, long, value_name = "COOKIE", value_parser = sanitize_cookie)]
+    cookie_override: Option<String>,
+    /// Use a specific user for checking. If this isn't specified, the default user will be used.
+    #[arg(short, long, value_name = "USER")]
+    user_override: Option<String>,
+    #[command(flatten)]
+    client: ClientParameters,
+    /// Re-download missing or damaged pages and remove leftover temporary files
+    #[arg(long)]
+    repair: bool,
+    /// If the directory is in use by another run, wait for it to finish instead of failing
+    #[arg(long)]
+    wait: bool,
+    /// Directory to check. If omitted, uses current directory
+    directory: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct TouchParameters {
+    /// Directly specify a cookie for use over everything else
+    #[arg(short, long, value_name = "COOKIE", value_parser = sanitize_cookie)]
+    cookie_override: Option<String>,
+    /// Use a specific user for looking up works. If this isn't specified, the default user will be used.
+    #[arg(short, long, value_name = "USER")]
+    user_override: Option<String>,
+    #[command(flatten)]
+    client: ClientParameters,
+    /// Which date of the works to use
+    #[arg(long, value_enum, default_value_t = FileDate::Created)]
+    date: FileDate,
+    /// If the directory is in use by another run, wait for it to finish instead of failing
+    #[arg(long)]
+    wait: bool,
+    /// Directory to update. If omitted, uses current directory
+    directory: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone)]
+pub enum FileDate {
+    /// When the work was first posted
+    Created,
+    /// When the work was last modified
+    Uploaded,
+}
+
+// -----
+
+#[derive(Parser, Debug)]
+pub struct DedupeParameters {
+    /// Libraries to deduplicate. If omitted, uses the configured library roots
+    roots: Vec<PathBuf>,
+    /// Override the configured way of linking pages
+    #[arg(short, long, value_enum, value_name = "METHOD")]
+    link_method: Option<LinkMethod>,
+    /// Only print what would be linked
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Default, Debug, Copy, Clone)]
+pub enum LinkMethod {
+    /// Both files point to the same data. Only works within the same filesystem
+    #[default]
+    Hardlink,
+    /// Copy-on-write clone of the data. Only on filesystems that support it
+    Reflink,
+    /// File points to the path of the other one
+    Symlink,
+}
+
+// -----
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigSubcommands {
+    /// Print the current configuration
+    Print,
+    /// Add a library that will be looked into for pages that were already downloaded
+    AddLibraryRoot { directory: PathBuf },
+    /// Remove a library root
+    RemoveLibraryRoot { directory: PathBuf },
+    /// Set how pages found in other libraries are linked
+    SetLinkMethod {
+        #[arg(value_enum)]
+        method: LinkMethod,
+    },
+    /// Set limits on HTTP requests. Only the given values are changed
+    SetLimits(LimitParameters),
+    /// Set proxies and certificates used by all users
+    SetNetwork(NetworkParameters),
+    /// Set where API requests and image downloads are sent
+    SetEndpoints(EndpointParameters),
+    /// Print the path of the configuration file
+    PrintPath,
+}
+
+/// Everything about how HTTP requests are made
+#[derive(Parser, Debug)]
+pub struct ClientParameters {
+    #[command(flatten)]
+    limits: LimitParameters,
+    #[command(flatten)]
+    endpoints: EndpointParameters,
+    #[command(flatten)]
+    recording: RecordingParameters,
+}
+
+#[derive(Parser, Debug)]
+pub struct RecordingParameters {
+    /// Save every API response received to this directory, for replaying later
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Serve API responses (and images) from a previous recording instead of using network
+    #[arg(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+    /// When recording, also save downloaded images
+    #[arg(long, requires = "record")]
+    record_images: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct LimitParameters {
+    /// Max API requests running at once. Overrides configuration
+    #[arg(long, value_name = "NB")]
+    max_api_requests: Option<usize>,
+    /// Max image downloads running at once. Overrides configuration
+    #[arg(long, value_name = "NB")]
+    max_image_downloads: Option<usize>,
+    /// Max requests started per second, 0 for no limit. Overrides configuration
+    #[arg(long, value_name = "NB", value_parser = parse_requests_per_second)]
+    requests_per_second: Option<f64>,
+}
+
+#[derive(Parser, Debug)]
+pub struct EndpointParameters {
+    /// Go back to pixiv's own servers before applying other values
+    #[arg(long)]
+    default_endpoints: bool,
+    /// Scheme and host to send API requests to instead of `https://www.pixiv.net`. Overrides configuration
+    #[arg(long, value_name = "URL")]
+    api_origin: Option<String>,
+    /// Rewrite the start of image URLs, as FROM=TO (e.g. `https://i.pximg.net=http://localhost:8080`). Overrides configuration
+    #[arg(long, value_name = "RULE")]
+    image_rewrite: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct NetworkParameters {
+    /// Start over from empty settings instead of adding to current ones
+    #[arg(long)]
+    clear: bool,
+    /// Proxy for all requests, e.g. `http://proxy:3128` or `socks5://127.0.0.1:1080`
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Proxy for plain HTTP requests only
+    #[arg(long, value_name = "URL")]
+    http_proxy: Option<String>,
+    /// Proxy for HTTPS requests only
+    #[arg(long, value_name = "URL")]
+    https_proxy: Option<String>,
+    /// PEM file with additional root certificates to trust. Can be repeated
+    #[arg(long, value_name = "FILE")]
+    ca_bundle: Vec<PathBuf>,
+    /// Proxy for a specific host and its sub-domains, as HOST=URL, or HOST=direct to bypass other proxies. Can be repeated
+    #[arg(long, value_name = "RULE")]
+    host_proxy: Vec<String>,
+}
+
+// -----
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    output::set_json(cli.json);
+    output::set_quiet(cli.quiet);
+
+    let result = match logging::init(cli.verbose, cli.quiet, cli.log_file.as_deref()) {
+        Ok(()) => run(cli.command).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            output::report_error(&e);
+            ExitCode::from(output::exit_code(&e))
+        }
+    }
+}
 
+async fn run(args: Args) -> Result<()> {
     match args {
         Args::Users(s) => do_users_subcommand(s).await,
         Args::Download(p) => do_download_subcommand(p).await,
+        Args::Get(p) => do_get_subcommand(p).await,
         Args::FindNotBookmarked(p) => do_fnb_subcommand(p).await,
         Args::CreateUpdateFile(c) => do_create_update_file_subcommand(c),
+        Args::Index(s) => do_index_subcommand(s).await,
+        Args::Verify(p) => do_verify_subcommand(p).await,
+        Args::Touch(p) => do_touch_subcommand(p).await,
+        Args::Dedupe(p) => do_dedupe_subcommand(p),
+        Args::Config(s) => do_config_subcommand(s),
     }
 }
@@ -0,0 +1,560 @@
+use std::{
+    fs::{read, rename, write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use pixiv_util::api_calls::illust::IllustInfo;
+
+/// What is written into image files
+pub struct WorkMetadata {
+    pub title: String,
+    pub creator: String,
+    pub tags: Vec<String>,
+    pub source_url: String,
+}
+
+impl WorkMetadata {
+    pub fn from_info(illust_id: u64, info: &IllustInfo) -> WorkMetadata {
+        WorkMetadata {
+            title: info.illust_title.clone(),
+            creator: info.user_name.clone(),
+            tags: info.tags.tags.iter().map(|t| t.tag.clone()).collect(),
+            source_url: format!("https://www.pixiv.net/artworks/{}", illust_id),
+        }
+    }
+
+    fn xmp(&self) -> String {
+        let tags: String = self
+            .tags
+            .iter()
+            .map(|t| format!("<rdf:li>{}</rdf:li>", escape_xml(t)))
+            .collect();
+
+        format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">",
+                "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+                "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+                "<dc:subject><rdf:Bag>{}</rdf:Bag></dc:subject>",
+                "<dc:source>{}</dc:source>",
+                "</rdf:Description>",
+                "</rdf:RDF>",
+                "</x:xmpmeta>",
+                "<?xpacket end=\"w\"?>"
+            ),
+            escape_xml(&self.title),
+            escape_xml(&self.creator),
+            tags,
+            escape_xml(&self.source_url),
+        )
+    }
+}
+
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write metadata into an image file, leaving pixel data untouched. Returns false if the format isn't supported
+pub fn embed_metadata(path: &Path, meta: &WorkMetadata) -> Result<bool> {
+    let data = read(path)?;
+
+    let new = if data.starts_with(&[0xFF, 0xD8]) {
+        embed_jpeg(&data, meta)?
+    } else if data.starts_with(PNG_SIGNATURE) {
+        embed_png(&data, meta)?
+    } else if data.starts_with(b"GIF8") {
+        embed_gif(&data, meta)?
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        embed_webp(&data, meta)?
+    } else {
+        return Ok(false);
+    };
+
+    // New file instead of writing in place, so that links to this file elsewhere aren't affected
+    let filename = path
+        .file_name()
+        .ok_or_else(|| anyhow!("No file name"))?
+        .to_string_lossy();
+    let temp = path.with_file_name(format!("._{}", filename));
+    write(&temp, new)?;
+    rename(temp, path)?;
+
+    Ok(true)
+}
+
+// ---------- JPEG
+
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PHOTOSHOP_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+
+/// Put XMP in APP1 and IPTC in APP13, after JFIF and EXIF segments. Previous XMP and IPTC are replaced
+fn embed_jpeg(data: &[u8], meta: &WorkMetadata) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid JPEG");
+
+    let mut kept_before = Vec::new();
+    let mut kept_after = Vec::new();
+    let mut pos = 2;
+
+    // Go through segments until image data
+    loop {
+        if data.get(pos) != Some(&0xFF) {
+            return Err(invalid());
+        }
+        let marker = *data.get(pos + 1).ok_or_else(invalid)?;
+        // Padding
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // Start of scan, everything after is copied as is
+        if marker == 0xDA {
+            break;
+        }
+
+        let len = u16::from_be_bytes([
+            *data.get(pos + 2).ok_or_else(invalid)?,
+            *data.get(pos + 3).ok_or_else(invalid)?,
+        ]) as usize;
+        // Length includes its own two bytes
+        if len < 2 {
+            return Err(invalid());
+        }
+        let segment = data.get(pos..pos + 2 + len).ok_or_else(invalid)?;
+        let payload = &segment[4..];
+
+        let is_xmp = marker == 0xE1 && payload.starts_with(XMP_NAMESPACE);
+        let is_iptc = marker == 0xED && payload.starts_with(PHOTOSHOP_SIGNATURE);
+        let goes_first = marker == 0xE0 || (marker == 0xE1 && !is_xmp);
+
+        if goes_first && kept_after.is_empty() {
+            kept_before.extend_from_slice(segment);
+        } else if !is_xmp && !is_iptc {
+            kept_after.extend_from_slice(segment);
+        }
+
+        pos += 2 + len;
+    }
+
+    let xmp = [XMP_NAMESPACE, meta.xmp().as_bytes()].concat();
+    let iptc = [PHOTOSHOP_SIGNATURE, &photoshop_iptc(meta)].concat();
+
+    let mut out = Vec::with_capacity(data.len() + xmp.len() + iptc.len() + 8);
+    out.extend_from_slice(&data[..2]);
+    out.extend_from_slice(&kept_before);
+    push_jpeg_segment(&mut out, 0xE1, &xmp)?;
+    push_jpeg_segment(&mut out, 0xED, &iptc)?;
+    out.extend_from_slice(&kept_after);
+    out.extend_from_slice(&data[pos..]);
+
+    Ok(out)
+}
+
+fn push_jpeg_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<()> {
+    let len = u16::try_from(payload.len() + 2).map_err(|_| anyhow!("Metadata too large"))?;
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// IPTC records wrapped in a Photoshop image resource
+fn photoshop_iptc(meta: &WorkMetadata) -> Vec<u8> {
+    let mut iptc = Vec::new();
+    let mut dataset = |record: u8, number: u8, value: &[u8]| {
+        // Longer values would need an extended length
+        let value = &value[..value.len().min(0x7FFF)];
+        iptc.extend_from_slice(&[0x1C, record, number]);
+        iptc.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        iptc.extend_from_slice(value);
+    };
+
+    // Text is UTF-8
+    dataset(1, 90, b"\x1B%G");
+    dataset(2, 0, &[0, 4]);
+    dataset(2, 5, meta.title.as_bytes());
+    dataset(2, 80, meta.creator.as_bytes());
+    for tag in &meta.tags {
+        dataset(2, 25, tag.as_bytes());
+    }
+    dataset(2, 115, meta.source_url.as_bytes());
+
+    let mut resource = Vec::with_capacity(iptc.len() + 13);
+    resource.extend_from_slice(b"8BIM");
+    resource.extend_from_slice(&0x0404u16.to_be_bytes());
+    // Empty name, padded to even length
+    resource.extend_from_slice(&[0, 0]);
+    resource.extend_from_slice(&(iptc.len() as u32).to_be_bytes());
+    resource.extend_from_slice(&iptc);
+    if iptc.len() % 2 == 1 {
+        resource.push(0);
+    }
+
+    resource
+}
+
+// ---------- PNG
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_KEYWORDS: [&str; 4] = ["Title", "Author", "Source", "XML:com.adobe.xmp"];
+
+/// Put iTXt chunks right after the header. Previous ones with the same keywords are replaced
+fn embed_png(data: &[u8], meta: &WorkMetadata) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid PNG");
+
+    let mut out = Vec::with_capacity(data.len() + 4096);
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let len =
+            u32::from_be_bytes(data.get(pos..pos + 4).ok_or_else(invalid)?.try_into()?) as usize;
+        let chunk = data.get(pos..pos + 12 + len).ok_or_else(invalid)?;
+        let kind = &chunk[4..8];
+        let body = &chunk[8..8 + len];
+
+        let replaced = kind == b"iTXt"
+            && PNG_KEYWORDS
+                .iter()
+                .any(|k| body.starts_with(k.as_bytes()) && body.get(k.len()) == Some(&0));
+        if !replaced {
+            out.extend_from_slice(chunk);
+        }
+
+        if kind == b"IHDR" {
+            let xmp = meta.xmp();
+            let texts = [
+                meta.title.as_str(),
+                meta.creator.as_str(),
+                meta.source_url.as_str(),
+                xmp.as_str(),
+            ];
+            for (keyword, text) in PNG_KEYWORDS.iter().zip(texts) {
+                push_png_itxt(&mut out, keyword, text);
+            }
+        }
+
+        pos += 12 + len;
+    }
+
+    Ok(out)
+}
+
+fn push_png_itxt(out: &mut Vec<u8>, keyword: &str, text: &str) {
+    let mut chunk = Vec::with_capacity(text.len() + keyword.len() + 9);
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(keyword.as_bytes());
+    // Null separator, uncompressed, no language, no translated keyword
+    chunk.extend_from_slice(&[0, 0, 0, 0, 0]);
+    chunk.extend_from_slice(text.as_bytes());
+
+    out.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// ---------- GIF
+
+const GIF_XMP_IDENTIFIER: &[u8] = b"XMP DataXMP";
+
+/// Put XMP in an application extension before the trailer. A previous one is replaced
+fn embed_gif(data: &[u8], meta: &WorkMetadata) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid GIF");
+
+    // Header and logical screen descriptor, then global color table
+    let flags = *data.get(10).ok_or_else(invalid)?;
+    let mut pos = 13;
+    if flags & 0x80 != 0 {
+        pos += 3 << ((flags & 0x07) + 1);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + 4096);
+    out.extend_from_slice(data.get(..pos).ok_or_else(invalid)?);
+
+    loop {
+        let start = pos;
+        match *data.get(pos).ok_or_else(invalid)? {
+            // Trailer
+            0x3B => break,
+            // Image
+            0x2C => {
+                let flags = *data.get(pos + 9).ok_or_else(invalid)?;
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 << ((flags & 0x07) + 1);
+                }
+                // LZW minimum code size
+                pos += 1;
+                pos = skip_gif_sub_blocks(data, pos).ok_or_else(invalid)?;
+            }
+            // Extension
+            0x21 => {
+                let is_xmp = data.get(pos + 1) == Some(&0xFF)
+                    && data.get(pos + 2) == Some(&11)
+                    && data.get(pos + 3..pos + 14) == Some(GIF_XMP_IDENTIFIER);
+                pos = skip_gif_sub_blocks(data, pos + 2).ok_or_else(invalid)?;
+                if is_xmp {
+                    continue;
+                }
+            }
+            _ => return Err(invalid()),
+        }
+        out.extend_from_slice(&data[start..pos]);
+    }
+
+    out.extend_from_slice(&[0x21, 0xFF, 11]);
+    out.extend_from_slice(GIF_XMP_IDENTIFIER);
+    out.extend_from_slice(meta.xmp().as_bytes());
+    // "Magic trailer", so that readers unaware of XMP can skip it as sub-blocks
+    out.push(0x01);
+    out.extend((0..=255u8).rev());
+    out.push(0x00);
+    out.extend_from_slice(&data[pos..]);
+
+    Ok(out)
+}
+
+/// Position after a sequence of sub-blocks and its terminator
+fn skip_gif_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// ---------- WebP
+
+/// Put XMP in its own chunk, switching to the extended format if needed. A previous one is replaced
+fn embed_webp(data: &[u8], meta: &WorkMetadata) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid WebP");
+
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let kind: [u8; 4] = data[pos..pos + 4].try_into()?;
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        // Chunks are padded to even length
+        let end = pos + 8 + len + len % 2;
+        let body = data.get(pos + 8..pos + 8 + len).ok_or_else(invalid)?;
+        if &kind != b"XMP " {
+            chunks.push((kind, body.to_vec()));
+        }
+        pos = end;
+    }
+
+    // Simple format has a single image chunk, and needs a VP8X header to have anything else
+    match chunks.first_mut() {
+        Some((kind, body)) if kind == b"VP8X" => *body.first_mut().ok_or_else(invalid)? |= 0x04,
+        Some((kind, body)) => {
+            let (width, height, alpha) = webp_dimensions(kind, body)
+                .filter(|(w, h, _)| *w > 0 && *h > 0)
+                .ok_or_else(invalid)?;
+            let mut vp8x = vec![0x04 | if alpha { 0x10 } else { 0 }, 0, 0, 0];
+            vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            chunks.insert(0, (*b"VP8X", vp8x));
+        }
+        None => return Err(invalid()),
+    }
+    chunks.push((*b"XMP ", meta.xmp().into_bytes()));
+
+    let mut body = b"WEBP".to_vec();
+    for (kind, data) in chunks {
+        body.extend_from_slice(&kind);
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+
+    Ok(out)
+}
+
+/// Width, height and presence of alpha of a simple WebP image
+fn webp_dimensions(kind: &[u8; 4], body: &[u8]) -> Option<(u32, u32, bool)> {
+    match kind {
+        // Lossy: frame tag, start code, then 14 bits sizes
+        b"VP8 " => {
+            let width = u16::from_le_bytes(body.get(6..8)?.try_into().ok()?) & 0x3FFF;
+            let height = u16::from_le_bytes(body.get(8..10)?.try_into().ok()?) & 0x3FFF;
+            Some((width as u32, height as u32, false))
+        }
+        // Lossless: signature, then 14 bits sizes minus one and alpha bit
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(body.get(1..5)?.try_into().ok()?);
+            let width = (bits & 0x3FFF) + 1;
+            let height = ((bits >> 14) & 0x3FFF) + 1;
+            Some((width, height, bits & (1 << 28) != 0))
+        }
+        _ => None,
+    }
+}
+
+// -----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> WorkMetadata {
+        WorkMetadata {
+            title: "Title & more".to_string(),
+            creator: "Someone".to_string(),
+            tags: vec!["cat".to_string(), "猫".to_string()],
+            source_url: "https://www.pixiv.net/artworks/123".to_string(),
+        }
+    }
+
+    /// Everything but metadata, to check that images are left untouched
+    fn png_image_chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < data.len() {
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let chunk = &data[pos..pos + 12 + len];
+            if &chunk[4..8] != b"iTXt" {
+                chunks.push(chunk.to_vec());
+            }
+            pos += 12 + len;
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_gets_text_chunks_and_keeps_pixels() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (kind, body) in [
+            (&b"IHDR"[..], &[0u8; 13][..]),
+            (b"IDAT", b"pixels"),
+            (b"IEND", b""),
+        ] {
+            let chunk = [kind, body].concat();
+            png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            png.extend_from_slice(&chunk);
+            png.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        }
+
+        let once = embed_png(&png, &meta()).unwrap();
+        let twice = embed_png(&once, &meta()).unwrap();
+        assert_eq!(once, twice);
+        assert_eq!(png_image_chunks(&once), png_image_chunks(&png));
+        assert!(String::from_utf8_lossy(&once).contains("Title &amp; more"));
+    }
+
+    #[test]
+    fn jpeg_gets_xmp_and_iptc_and_keeps_pixels() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        push_jpeg_segment(&mut jpeg, 0xE0, b"JFIF\0stuff").unwrap();
+        push_jpeg_segment(&mut jpeg, 0xDB, b"tables").unwrap();
+        let scan = [0xFF, 0xDA, 0, 4, 1, 2, 0x12, 0x34, 0xFF, 0xD9];
+        jpeg.extend_from_slice(&scan);
+
+        let once = embed_jpeg(&jpeg, &meta()).unwrap();
+        let twice = embed_jpeg(&once, &meta()).unwrap();
+        assert_eq!(once, twice);
+        assert!(once.ends_with(&scan));
+        // JFIF stays first
+        assert_eq!(&once[2..16], &jpeg[2..16]);
+        assert!(once
+            .windows(XMP_NAMESPACE.len())
+            .any(|w| w == XMP_NAMESPACE));
+
+        // Segment lengths too short to cover themselves
+        for len in [0, 1] {
+            let broken = [0xFF, 0xD8, 0xFF, 0xE0, 0, len, 0xFF, 0xDA];
+            assert!(embed_jpeg(&broken, &meta()).is_err());
+        }
+    }
+
+    #[test]
+    fn gif_gets_xmp_and_keeps_frames() {
+        let mut gif = b"GIF89a".to_vec();
+        // Screen of 1x1 with a global color table of 2 colors
+        gif.extend_from_slice(&[1, 0, 1, 0, 0x80, 0, 0]);
+        gif.extend_from_slice(&[0, 0, 0, 0xFF, 0xFF, 0xFF]);
+        let frame = [
+            0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0x02, 0x02, 0x44, 0x01, 0x00,
+        ];
+        gif.extend_from_slice(&frame);
+        gif.push(0x3B);
+
+        let once = embed_gif(&gif, &meta()).unwrap();
+        let twice = embed_gif(&once, &meta()).unwrap();
+        assert_eq!(once, twice);
+        assert!(once.starts_with(&gif[..gif.len() - 1]));
+        assert!(once.ends_with(&[0x3B]));
+        assert!(once
+            .windows(GIF_XMP_IDENTIFIER.len())
+            .any(|w| w == GIF_XMP_IDENTIFIER));
+
+        // Cut before the trailer
+        assert!(embed_gif(&gif[..gif.len() - 3], &meta()).is_err());
+    }
+
+    fn webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        for (kind, data) in chunks {
+            body.extend_from_slice(*kind);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        [
+            b"RIFF".as_slice(),
+            &(body.len() as u32).to_le_bytes(),
+            &body,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn webp_gets_extended_header_and_xmp() {
+        // Lossless image of 2x3 with alpha
+        let bits: u32 = 1 | (2 << 14) | (1 << 28);
+        let image = [&[0x2F][..], &bits.to_le_bytes()].concat();
+        let simple = webp(&[(b"VP8L", &image)]);
+
+        let once = embed_webp(&simple, &meta()).unwrap();
+        let twice = embed_webp(&once, &meta()).unwrap();
+        assert_eq!(once, twice);
+        assert_eq!(&once[12..16], b"VP8X");
+        // Flags for XMP and alpha, then sizes minus one
+        assert_eq!(&once[20..30], &[0x14, 0, 0, 0, 1, 0, 0, 2, 0, 0]);
+        assert!(once.windows(image.len()).any(|w| w == image));
+
+        // Broken headers are rejected instead of trusted
+        assert!(embed_webp(&webp(&[(b"VP8X", &[])]), &meta()).is_err());
+        assert!(embed_webp(&webp(&[(b"VP8 ", &[0; 10])]), &meta()).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    fmt::Arguments,
+    io::{stdout, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Error;
+use serde::Serialize;
+
+use pixiv_util::ApiError;
+
+use crate::verify::ImageProblem;
+
+/// Whether output is meant for scripts
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Whether messages for humans are left out
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Print a value as a single line of JSON
+pub fn print_json(value: &impl Serialize) {
+    // Lines from concurrent downloads must not get mixed up
+    let mut out = stdout().lock();
+    if serde_json::to_writer(&mut out, value).is_ok() {
+        let _ = writeln!(out);
+    }
+}
+
+/// Print a message for humans. It goes to stderr with `--json`, so that stdout can be parsed
+pub fn print_message(args: Arguments) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_json() {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// Same as `println!`, but out of the way of JSON output
+macro_rules! message {
+    ($($arg:tt)*) => {
+        $crate::output::print_message(format_args!($($arg)*))
+    };
+}
+pub(crate) use message;
+
+// ---------- Events
+
+/// What happens during a command, printed as NDJSON with `--json`
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// Source listed a work
+    WorkDiscovered { illust_id: u64 },
+    /// Page was downloaded, or linked from another library
+    PageSaved {
+        illust_id: u64,
+        page: usize,
+        path: &'a Path,
+    },
+    /// Work was already downloaded
+    Skipped { illust_id: u64, reason: SkipReason },
+    /// Work couldn't be downloaded
+    Failed { illust_id: u64, error: String },
+    /// Novel was saved to a file
+    NovelSaved { novel_id: u64, path: &'a Path },
+    /// Source was downloaded entirely, or until interrupted
+    Finished {
+        dest_dir: &'a Path,
+        works: usize,
+        downloaded: usize,
+        skipped: usize,
+        failed: usize,
+        interrupted: bool,
+    },
+    /// Temporary file left by an interrupted download, found by `verify`
+    LeftoverFile { path: &'a Path },
+    /// Page missing or damaged, found by `verify`
+    PageProblem {
+        illust_id: u64,
+        page: usize,
+        problem: ImageProblem,
+    },
+    /// Library was checked, and maybe repaired
+    Verified { problems: usize, repaired: usize },
+    /// Times of files were changed to those of their works
+    Touched { files: usize },
+    /// Copy of a page was replaced by a link to the original, or would be with `--dry-run`
+    DuplicateLinked { copy: &'a Path, original: &'a Path },
+    /// Libraries were deduplicated
+    Deduplicated {
+        linked: usize,
+        failed: usize,
+        bytes_saved: u64,
+        dry_run: bool,
+    },
+    /// Archive of a library was made again from its files
+    Indexed { works: usize },
+    /// Command failed
+    Error {
+        message: String,
+        kind: Option<&'static str>,
+        status_code: Option<u16>,
+        exit_code: u8,
+    },
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Recorded in the archive
+    Archived,
+    /// All pages found on disk
+    OnDisk,
+    /// Done before the download was interrupted
+    Resumed,
+}
+
+/// Print an event, only with `--json`
+pub fn emit(event: Event) {
+    if is_json() {
+        print_json(&event);
+    }
+}
+
+// ---------- Errors
+
+/// Process exit codes, so that scripts can tell what went wrong
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Other error
+  2  Invalid arguments
+  3  Network problem
+  4  Work or user not found, or deleted
+  5  Login required, e.g. for R-18 works
+  6  Forbidden for this account, e.g. because of its age settings
+  7  Rate limited by pixiv
+  8  Session expired, the cookie of the user must be renewed
+  9  Unexpected response, the API may have changed
+ 10  Server error or empty response, pixiv may be having trouble
+ 11  Refused by pixiv for another reason, see the message";
+
+/// Exit code for an error, see `EXIT_CODES`
+pub fn exit_code(e: &Error) -> u8 {
+    let Some(api_error) = find_api_error(e) else {
+        return 1;
+    };
+    match api_error {
+        ApiError::Network(_) => 3,
+        ApiError::NotFound { .. } => 4,
+        ApiError::LoginRequired { .. } => 5,
+        ApiError::Forbidden { .. } => 6,
+        ApiError::RateLimited { .. } => 7,
+        ApiError::ExpiredSession { .. } => 8,
+        ApiError::JSONParse(_) | ApiError::SchemaDrift { .. } => 9,
+        ApiError::EmptyResponse { .. } => 10,
+        ApiError::ServerApplication { status_code, .. } | ApiError::ServerHTTP { status_code }
+            if status_code.is_server_error() =>
+        {
+            10
+        }
+        ApiError::ServerApplication { .. } | ApiError::ServerHTTP { .. } => 11,
+        ApiError::NotRecorded { .. } | ApiError::Recording(_) => 1,
+    }
+}
+
+fn find_api_error(e: &Error) -> Option<&ApiError> {
+    e.chain().find_map(|c| c.downcast_ref::<ApiError>())
+}
+
+/// Print the error a command ended with
+pub fn report_error(e: &Error) {
+    if !is_json() {
+        eprintln!("Error: {:?}", e);
+        return;
+    }
+    let api_error = find_api_error(e);
+    print_json(&Event::Error {
+        message: format!("{:#}", e),
+        kind: api_error.map(|a| a.kind()),
+        status_code: api_error.and_then(|a| a.status_code()).map(|s| s.as_u16()),
+        exit_code: exit_code(e),
+    });
+}
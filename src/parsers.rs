@@ -1,4 +1,9 @@
 use anyhow::{anyhow, Result};
+use reqwest::Url;
+
+use pixiv_util::{gen_http_client::MIN_REQUESTS_PER_SECOND, PixivClient};
+
+use crate::{DownloadIllustModes, SearchRating, TagMatch};
 
 pub fn sanitize_cookie(cookie: &str) -> Result<String> {
     // TODO: Remove useless fields
@@ -18,24 +23,32 @@ pub fn sanitize_cookie(cookie: &str) -> Result<String> {
     }
 }
 
+pub fn parse_requests_per_second(s: &str) -> Result<f64> {
+    let rate: f64 = s.parse()?;
+    if rate == 0.0 || (rate.is_finite() && rate >= MIN_REQUESTS_PER_SECOND) {
+        Ok(rate)
+    } else {
+        Err(anyhow!(
+            "must be 0 for no limit, or a number of at least {}",
+            MIN_REQUESTS_PER_SECOND
+        ))
+    }
+}
+
 pub fn parse_illust_id(s: &str) -> Result<u64> {
     // Is a straight id
     if let Ok(v) = s.parse() {
         return Ok(v);
     }
 
-    // Is a URL like https://www.pixiv.net/en/artworks/{illust_id}#*
-    if let Some(part) = s.split_once("artworks/") {
-        let id_s = match part.1.split_once('#') {
-            Some((s, _)) => s,
-            None => part.1,
-        };
-        if let Ok(v) = id_s.parse() {
-            return Ok(v);
+    // Is a URL of an artwork or one of its images
+    match parse_pixiv_url(s) {
+        Ok(UrlTarget::Illusts(DownloadIllustModes::Individual { illust_ids, .. })) => {
+            Ok(illust_ids[0])
         }
+        Ok(_) => Err(anyhow!("cannot recognize illust id")),
+        Err(e) => Err(anyhow!("cannot recognize illust id: {}", e)),
     }
-
-    Err(anyhow!("cannot recognize illust id"))
 }
 
 pub fn parse_series_id(s: &str) -> Result<u64> {
@@ -44,14 +57,13 @@ pub fn parse_series_id(s: &str) -> Result<u64> {
         return Ok(v);
     }
 
-    // Is a URL like https://www.pixiv.net/user/*/series/{series_id}
-    if let Some(part) = s.split_once("series/") {
-        if let Ok(v) = part.1.parse() {
-            return Ok(v);
-        }
+    // Is a URL of a series of illusts or novels
+    match parse_pixiv_url(s) {
+        Ok(UrlTarget::Illusts(DownloadIllustModes::Series { series_id }))
+        | Ok(UrlTarget::NovelSeries(series_id)) => Ok(series_id),
+        Ok(_) => Err(anyhow!("cannot recognize series id")),
+        Err(e) => Err(anyhow!("cannot recognize series id: {}", e)),
     }
-
-    Err(anyhow!("cannot recognize series id"))
 }
 
 pub fn parse_user_id(s: &str) -> Result<u64> {
@@ -60,16 +72,420 @@ pub fn parse_user_id(s: &str) -> Result<u64> {
         return Ok(v);
     }
 
-    // Is a URL like https://www.pixiv.net/en/users/{user_id}/*
-    if let Some(part) = s.split_once("users/") {
-        let id_s = match part.1.split_once('/') {
-            Some((s, _)) => s,
-            None => part.1,
-        };
-        if let Ok(v) = id_s.parse() {
-            return Ok(v);
+    // Is a URL of a user page, or of one of its tabs
+    match parse_pixiv_url(s) {
+        Ok(UrlTarget::Illusts(
+            DownloadIllustModes::UserPosts { user_id, .. }
+            | DownloadIllustModes::UserBookmarks { user_id },
+        )) => Ok(user_id),
+        Ok(UrlTarget::Vanity(_)) => Err(anyhow!(
+            "cannot recognize user id: pixiv.me links are only followed by `get` and input files"
+        )),
+        Ok(_) => Err(anyhow!("cannot recognize user id")),
+        Err(e) => Err(anyhow!("cannot recognize user id: {}", e)),
+    }
+}
+
+pub fn parse_novel_id(s: &str) -> Result<u64> {
+    // Is a straight id
+    if let Ok(v) = s.parse() {
+        return Ok(v);
+    }
+
+    // Is a URL like https://www.pixiv.net/novel/show.php?id={novel_id}
+    match parse_pixiv_url(s) {
+        Ok(UrlTarget::Novel(novel_id)) => Ok(novel_id),
+        Ok(_) => Err(anyhow!("cannot recognize novel id")),
+        Err(e) => Err(anyhow!("cannot recognize novel id: {}", e)),
+    }
+}
+
+// ---------- Any URL
+
+/// What a pixiv URL points to
+#[derive(Debug, Clone, PartialEq)]
+pub enum UrlTarget {
+    Illusts(DownloadIllustModes),
+    Novel(u64),
+    NovelSeries(u64),
+    /// Name in a vanity link such as `pixiv.me/name`, which must be followed to know the user
+    Vanity(String),
+}
+
+/// Find what to download from any pixiv URL, e.g. `https://www.pixiv.net/en/users/123/bookmarks/artworks`. Page numbers and fragments are ignored
+pub fn parse_pixiv_url(s: &str) -> Result<UrlTarget> {
+    // Scheme is often left out when copying from the address bar
+    let url = if s.contains("://") {
+        Url::parse(s)
+    } else {
+        Url::parse(&format!("https://{}", s))
+    }
+    .map_err(|e| anyhow!("not a URL: {}", e))?;
+
+    let mut segments: Vec<String> = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect::<Result<_>>()?;
+
+    match url.host_str() {
+        // Site itself, mobile site
+        Some("www.pixiv.net" | "pixiv.net" | "touch.pixiv.net") => {}
+        // Images are named after their illust, e.g. `.../img-original/img/2020/01/01/00/00/00/1234_p0.png`
+        Some("i.pximg.net") => {
+            let id = segments
+                .last()
+                .and_then(|name| name.split(['_', '.']).next())
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| anyhow!("not the URL of an image of an illust"))?;
+            return Ok(UrlTarget::Illusts(DownloadIllustModes::Individual {
+                whole_series: false,
+                illust_ids: vec![id],
+            }));
         }
+        // Vanity links lead to user pages, once followed
+        Some("pixiv.me") => {
+            let [name] = segments.as_slice() else {
+                return Err(anyhow!("not a pixiv.me link to a user"));
+            };
+            return Ok(UrlTarget::Vanity(name.clone()));
+        }
+        _ => return Err(anyhow!("not a pixiv URL")),
     }
 
-    Err(anyhow!("cannot recognize user id"))
+    // Language of the site, e.g. `/en/artworks/...` or `/zh-tw/artworks/...`
+    if segments.first().is_some_and(|s| is_locale(s)) {
+        segments.remove(0);
+    }
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    let id = |s: &str| -> Result<u64> { s.parse().map_err(|_| anyhow!("invalid ID `{}`", s)) };
+    let query_id = |key: &str| -> Result<u64> {
+        id(&query(key).ok_or_else(|| anyhow!("missing `{}` in URL", key))?)
+    };
+
+    let mode = match segments.as_slice() {
+        ["artworks", illust_id] | ["i", illust_id] => DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids: vec![id(illust_id)?],
+        },
+        ["member_illust.php"] if query("illust_id").is_some() => DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids: vec![query_id("illust_id")?],
+        },
+
+        // Illustration and manga tabs both give all posts
+        ["users", user_id] | ["users", user_id, "artworks" | "illustrations" | "manga"] => {
+            DownloadIllustModes::UserPosts {
+                tag: None,
+                user_id: id(user_id)?,
+            }
+        }
+        ["users", user_id, "artworks" | "illustrations" | "manga", tag] => {
+            DownloadIllustModes::UserPosts {
+                tag: Some(tag.to_string()),
+                user_id: id(user_id)?,
+            }
+        }
+        ["member.php" | "member_illust.php"] => DownloadIllustModes::UserPosts {
+            tag: None,
+            user_id: query_id("id")?,
+        },
+        // Private bookmarks can only be seen by their owner
+        ["users", user_id, "bookmarks", "artworks", ..] => match query("rest").as_deref() {
+            Some("hide") => DownloadIllustModes::OwnBookmarks {
+                public: false,
+                private: true,
+            },
+            _ => DownloadIllustModes::UserBookmarks {
+                user_id: id(user_id)?,
+            },
+        },
+        ["user", _, "series", series_id] => DownloadIllustModes::Series {
+            series_id: id(series_id)?,
+        },
+
+        ["novel", "show.php"] => return Ok(UrlTarget::Novel(query_id("id")?)),
+        ["novel", "series", series_id] => return Ok(UrlTarget::NovelSeries(id(series_id)?)),
+
+        ["tags", tag] | ["tags", tag, "artworks" | "illustrations" | "manga" | "top"] => {
+            DownloadIllustModes::Search {
+                matching: match query("s_mode").as_deref() {
+                    Some("s_tag_full") => TagMatch::Exact,
+                    Some("s_tc") => TagMatch::TitleCaption,
+                    _ => TagMatch::Partial,
+                },
+                rating: match query("mode").as_deref() {
+                    Some("safe") => SearchRating::Safe,
+                    Some("r18") => SearchRating::R18,
+                    _ => SearchRating::All,
+                },
+                tag: tag.to_string(),
+            }
+        }
+        ["ranking.php"] => DownloadIllustModes::Ranking {
+            mode: query("mode").unwrap_or_else(|| "daily".to_string()),
+            content: query("content"),
+            date: query("date"),
+        },
+
+        _ => return Err(anyhow!("don't know how to download this URL")),
+    };
+
+    Ok(UrlTarget::Illusts(mode))
+}
+
+/// Decode `%XX` sequences, e.g. in tags
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| anyhow!("invalid escape in `{}`", s))?;
+            out.push(hex);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| anyhow!("invalid text in `{}`", s))
+}
+
+/// Whether a path segment is a language such as `en` or `zh-tw`
+fn is_locale(s: &str) -> bool {
+    let is_code = |p: &str| p.len() == 2 && p.bytes().all(|b| b.is_ascii_lowercase());
+    match s.split_once('-') {
+        Some((language, region)) => is_code(language) && is_code(region),
+        None => is_code(s),
+    }
+}
+
+/// Find the posts of the user a vanity link such as `pixiv.me/name` leads to
+pub async fn resolve_vanity(client: &PixivClient, name: &str) -> Result<DownloadIllustModes> {
+    let location = client.vanity_destination(name).await?;
+
+    match parse_pixiv_url(&location) {
+        Ok(UrlTarget::Illusts(mode @ DownloadIllustModes::UserPosts { .. })) => Ok(mode),
+        _ => Err(anyhow!("`pixiv.me/{}` doesn't lead to a user", name)),
+    }
+}
+
+// -----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a parser over a table of inputs and what they should give, `None` if they should be rejected
+    fn check<T: PartialEq + std::fmt::Debug>(
+        parser: fn(&str) -> Result<T>,
+        table: &[(&str, Option<T>)],
+    ) {
+        for (input, expected) in table {
+            assert_eq!(parser(input).ok().as_ref(), expected.as_ref(), "{}", input);
+        }
+    }
+
+    fn individual(id: u64) -> Option<UrlTarget> {
+        Some(UrlTarget::Illusts(DownloadIllustModes::Individual {
+            whole_series: false,
+            illust_ids: vec![id],
+        }))
+    }
+
+    #[test]
+    fn illust_ids() {
+        check(
+            parse_illust_id,
+            &[
+                ("12345", Some(12345)),
+                ("https://www.pixiv.net/artworks/12345", Some(12345)),
+                ("https://www.pixiv.net/en/artworks/12345", Some(12345)),
+                ("https://www.pixiv.net/zh-tw/artworks/12345#big_0", Some(12345)),
+                ("www.pixiv.net/artworks/12345?p=2", Some(12345)),
+                ("https://www.pixiv.net/i/12345", Some(12345)),
+                (
+                    "https://www.pixiv.net/member_illust.php?mode=medium&illust_id=12345",
+                    Some(12345),
+                ),
+                (
+                    "https://touch.pixiv.net/member_illust.php?mode=medium&illust_id=12345",
+                    Some(12345),
+                ),
+                ("https://touch.pixiv.net/artworks/12345", Some(12345)),
+                (
+                    "https://i.pximg.net/img-original/img/2020/01/01/00/00/00/12345_p0.png",
+                    Some(12345),
+                ),
+                (
+                    "https://i.pximg.net/img-master/img/2020/01/01/00/00/00/12345_p3_master1200.jpg",
+                    Some(12345),
+                ),
+                (
+                    "https://i.pximg.net/img-zip-ugoira/img/2020/01/01/00/00/00/12345_ugoira1920x1080.zip",
+                    Some(12345),
+                ),
+                ("abc", None),
+                ("-1", None),
+                ("https://www.pixiv.net/artworks/abc", None),
+                ("https://www.pixiv.net/users/12345", None),
+                ("https://www.pixiv.net/member_illust.php?mode=medium", None),
+                ("https://example.com/artworks/12345", None),
+                ("https://i.pximg.net/common/images/no_profile.png", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn user_ids() {
+        check(
+            parse_user_id,
+            &[
+                ("5", Some(5)),
+                ("https://www.pixiv.net/users/5", Some(5)),
+                ("https://www.pixiv.net/en/users/5/illustrations", Some(5)),
+                ("https://www.pixiv.net/users/5/manga?p=3", Some(5)),
+                ("https://www.pixiv.net/users/5/artworks/%E7%8C%AB", Some(5)),
+                ("https://www.pixiv.net/users/5/bookmarks/artworks", Some(5)),
+                ("https://www.pixiv.net/member.php?id=5", Some(5)),
+                ("https://touch.pixiv.net/member.php?id=5", Some(5)),
+                ("https://www.pixiv.net/users/abc", None),
+                ("https://www.pixiv.net/artworks/5", None),
+                ("https://www.pixiv.net/member.php", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn series_ids() {
+        check(
+            parse_series_id,
+            &[
+                ("7", Some(7)),
+                ("https://www.pixiv.net/user/5/series/7", Some(7)),
+                ("https://www.pixiv.net/en/user/5/series/7?p=2", Some(7)),
+                ("https://www.pixiv.net/novel/series/9", Some(9)),
+                ("https://www.pixiv.net/users/5", None),
+                ("https://www.pixiv.net/user/5/series", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn novel_ids() {
+        check(
+            parse_novel_id,
+            &[
+                ("11", Some(11)),
+                ("https://www.pixiv.net/novel/show.php?id=11", Some(11)),
+                ("https://www.pixiv.net/en/novel/show.php?id=11#2", Some(11)),
+                ("https://touch.pixiv.net/novel/show.php?id=11", Some(11)),
+                ("https://www.pixiv.net/novel/show.php", None),
+                ("https://www.pixiv.net/artworks/11", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn pixiv_urls() {
+        use DownloadIllustModes::*;
+
+        check(
+            parse_pixiv_url,
+            &[
+                ("https://www.pixiv.net/ja/artworks/1", individual(1)),
+                (
+                    "https://www.pixiv.net/users/5/artworks/%E7%8C%AB%20a%26b",
+                    Some(UrlTarget::Illusts(UserPosts {
+                        tag: Some("猫 a&b".to_string()),
+                        user_id: 5,
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/users/5/bookmarks/artworks?rest=hide",
+                    Some(UrlTarget::Illusts(OwnBookmarks {
+                        public: false,
+                        private: true,
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/tags/%E7%8C%AB/artworks?s_mode=s_tag_full&mode=safe&p=4",
+                    Some(UrlTarget::Illusts(Search {
+                        matching: TagMatch::Exact,
+                        rating: SearchRating::Safe,
+                        tag: "猫".to_string(),
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/en/tags/cat",
+                    Some(UrlTarget::Illusts(Search {
+                        matching: TagMatch::Partial,
+                        rating: SearchRating::All,
+                        tag: "cat".to_string(),
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/ranking.php?mode=weekly&content=manga&date=20240101",
+                    Some(UrlTarget::Illusts(Ranking {
+                        mode: "weekly".to_string(),
+                        content: Some("manga".to_string()),
+                        date: Some("20240101".to_string()),
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/ranking.php",
+                    Some(UrlTarget::Illusts(Ranking {
+                        mode: "daily".to_string(),
+                        content: None,
+                        date: None,
+                    })),
+                ),
+                (
+                    "https://www.pixiv.net/novel/show.php?id=11",
+                    Some(UrlTarget::Novel(11)),
+                ),
+                (
+                    "https://www.pixiv.net/novel/series/9",
+                    Some(UrlTarget::NovelSeries(9)),
+                ),
+                ("https://www.pixiv.net/tags/%E7%8C", None),
+                ("https://www.pixiv.net/users/5/followers", None),
+                (
+                    "pixiv.me/someone",
+                    Some(UrlTarget::Vanity("someone".to_string())),
+                ),
+                ("https://pixiv.me/", None),
+                ("https://pixiv.me/someone/else", None),
+                ("https://www.pixiv.net.example.com/artworks/1", None),
+                ("not a url at all", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn locales() {
+        for (segment, expected) in [
+            ("en", true),
+            ("ja", true),
+            ("zh-tw", true),
+            ("i", false),
+            ("EN", false),
+            ("tags", false),
+            ("en-", false),
+        ] {
+            assert_eq!(is_locale(segment), expected, "{}", segment);
+        }
+    }
 }
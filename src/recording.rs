@@ -0,0 +1,227 @@
+use std::path::{Path, PathBuf};
+
+use std::io::{Error, ErrorKind, Result};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::{copy, create_dir_all, read, write};
+
+use crate::transport::Response;
+
+/// Headers worth keeping. Recordings are shared in bug reports, so anything like `Set-Cookie` must stay out
+const RECORDED_HEADERS: [HeaderName; 2] = [CONTENT_TYPE, RETRY_AFTER];
+
+/// Saves HTTP responses to a directory, or serves them back from it instead of using the network
+pub struct Recording {
+    dir: PathBuf,
+    mode: RecordingMode,
+    /// Also save image downloads when recording
+    images: bool,
+}
+
+#[derive(PartialEq)]
+enum RecordingMode {
+    Record,
+    Replay,
+}
+
+/// One API response, saved as a JSON file named after the hash of its URL
+#[derive(Serialize, Deserialize)]
+struct RecordedResponse {
+    url: String,
+    /// Where the response came from, after any redirects
+    #[serde(default)]
+    final_url: String,
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Recording {
+    /// Save responses to `dir`, which is created if needed
+    pub fn record(dir: PathBuf, images: bool) -> Result<Recording> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Recording {
+            dir,
+            mode: RecordingMode::Record,
+            images,
+        })
+    }
+
+    /// Serve responses from a previous recording in `dir`
+    pub fn replay(dir: PathBuf) -> Result<Recording> {
+        if !dir.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no recording in `{}` to replay", dir.display()),
+            ));
+        }
+        Ok(Recording {
+            dir,
+            mode: RecordingMode::Replay,
+            images: false,
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == RecordingMode::Replay
+    }
+
+    fn path_for(&self, url: &str, extension: &str) -> PathBuf {
+        let hash = Sha256::digest(url.as_bytes());
+        self.dir.join(format!("{:x}.{}", hash, extension))
+    }
+
+    /// Save an API response, if recording
+    pub async fn save_response(&self, url: &str, resp: &Response) -> Result<()> {
+        if self.mode != RecordingMode::Record {
+            return Ok(());
+        }
+
+        let recorded = RecordedResponse {
+            url: url.to_string(),
+            final_url: resp.url.clone(),
+            status: resp.status.as_u16(),
+            headers: resp
+                .headers
+                .iter()
+                .filter(|(k, _)| RECORDED_HEADERS.contains(k))
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+            body: String::from_utf8_lossy(&resp.body).into_owned(),
+        };
+        write(
+            self.path_for(url, "json"),
+            serde_json::to_vec_pretty(&recorded)?,
+        )
+        .await
+    }
+
+    /// Get back a saved API response. `None` if it was never recorded
+    pub async fn load_response(&self, url: &str) -> Result<Option<Response>> {
+        let path = self.path_for(url, "json");
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let invalid = |e| Error::new(ErrorKind::InvalidData, e);
+
+        let recorded: RecordedResponse = serde_json::from_slice(&read(path).await?)?;
+        let status = StatusCode::from_u16(recorded.status).map_err(invalid)?;
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in recorded.headers {
+            if let (Ok(k), Ok(v)) = (HeaderName::try_from(k), HeaderValue::try_from(v)) {
+                headers.append(k, v);
+            }
+        }
+
+        Ok(Some(Response {
+            url: recorded.final_url,
+            status,
+            headers,
+            body: recorded.body.into(),
+        }))
+    }
+
+    /// Keep a copy of a downloaded image, if recording them
+    pub async fn save_image(&self, url: &str, path: &Path) -> Result<()> {
+        if self.mode != RecordingMode::Record || !self.images {
+            return Ok(());
+        }
+
+        create_dir_all(&self.dir).await?;
+        copy(path, self.path_for(url, "bin")).await?;
+
+        Ok(())
+    }
+
+    /// Put a saved image at `dest`
+    pub async fn load_image(&self, url: &str, dest: &Path) -> Result<()> {
+        let path = self.path_for(url, "bin");
+        if !path.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "no recorded image for `{}`, record with --record-images to replay downloads",
+                    url
+                ),
+            ));
+        }
+
+        copy(path, dest).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use reqwest::header::SET_COOKIE;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        gen_http_client::{Endpoints, Limits},
+        transport::MemoryTransport,
+        PixivClient,
+    };
+
+    #[tokio::test]
+    async fn recorded_responses_are_replayed() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let client = |transport, recording| {
+            PixivClient::with_transport(
+                Arc::new(transport),
+                Limits::default(),
+                Endpoints::default(),
+                Some(recording),
+            )
+        };
+
+        let mut transport = MemoryTransport::default();
+        transport.insert(
+            "https://www.pixiv.net/ajax/illust/20/pages",
+            StatusCode::OK,
+            r#"{"error":false,"message":"","body":[{"urls":{"small":"s","regular":"r","original":"o"},"width":1,"height":2}]}"#,
+        );
+        let recorded = client(transport, Recording::record(dir.clone(), false).unwrap())
+            .illust_pages(20)
+            .await
+            .unwrap();
+
+        // Nothing from the network this time
+        let replayed = client(
+            MemoryTransport::default(),
+            Recording::replay(dir.clone()).unwrap(),
+        )
+        .illust_pages(20)
+        .await
+        .unwrap();
+        assert_eq!(recorded, replayed);
+
+        // Session cookies are left out
+        let recording = Recording::record(dir.clone(), false).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(SET_COOKIE, HeaderValue::from_static("PHPSESSID=secret"));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        let resp = Response {
+            url: "https://www.pixiv.net/limited".to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            body: "".into(),
+        };
+        recording.save_response("/limited", &resp).await.unwrap();
+        let loaded = recording.load_response("/limited").await.unwrap().unwrap();
+        assert!(loaded.headers.get(SET_COOKIE).is_none());
+        assert_eq!(loaded.headers.get(RETRY_AFTER).unwrap(), "30");
+    }
+}
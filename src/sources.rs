@@ -0,0 +1,455 @@
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use futures::stream::{self, select, Stream, StreamExt, TryStreamExt};
+
+use crate::{api_calls::user_bookmarks::Visibility, ApiError, PixivClient};
+
+/// Pages fetched ahead of the one being consumed
+pub const DEFAULT_PREFETCH: usize = 4;
+
+/// Maximum allowed by API for offset-based endpoints
+const WORKS_PER_PAGE: usize = 100;
+/// Maximum allowed by API for novels of a series
+const NOVELS_PER_PAGE: usize = 30;
+
+pub type WorkStream = Pin<Box<dyn Stream<Item = Result<WorkRef, ApiError>> + Send>>;
+
+/// One work found in a collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkRef {
+    pub id: u64,
+    /// Position given by the collection itself, e.g. in a series
+    pub order: Option<usize>,
+}
+
+impl WorkRef {
+    pub fn new(id: u64) -> WorkRef {
+        WorkRef { id, order: None }
+    }
+}
+
+/// What a collection says about itself, e.g. a series
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionInfo {
+    pub title: String,
+    pub description: String,
+    pub cover_url: Option<String>,
+}
+
+/// A set of works, which are fetched as they are consumed. Dropping the stream stops fetching
+pub struct Collection {
+    pub info: Option<CollectionInfo>,
+    /// Number of works as reported by the server. May include some that won't be given, e.g. deleted ones
+    pub total: usize,
+    pub works: WorkStream,
+}
+
+impl Collection {
+    /// Collection of known works, without any request
+    pub fn from_ids(ids: Vec<u64>) -> Collection {
+        Collection {
+            info: None,
+            total: ids.len(),
+            works: Box::pin(stream::iter(ids.into_iter().map(|i| Ok(WorkRef::new(i))))),
+        }
+    }
+
+    /// Works of both collections, as they come. Info is lost
+    pub fn merge(self, other: Collection) -> Collection {
+        Collection {
+            info: None,
+            total: self.total + other.total,
+            works: Box::pin(select(self.works, other.works)),
+        }
+    }
+}
+
+// ---------- Pagination
+
+/// How to ask for a page of a paginated endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cursor {
+    /// Skip `offset` works, get up to `limit` of them
+    Offset { offset: usize, limit: usize },
+    /// Page number, starting at 1
+    Page(usize),
+}
+
+/// How pages of an endpoint are addressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pagination {
+    Offset { limit: usize },
+    Numbered,
+}
+
+impl Pagination {
+    fn cursor(self, index: usize) -> Cursor {
+        match self {
+            Pagination::Offset { limit } => Cursor::Offset {
+                offset: index * limit,
+                limit,
+            },
+            Pagination::Numbered => Cursor::Page(index + 1),
+        }
+    }
+}
+
+/// One page as returned by an endpoint
+pub struct SourcePage {
+    pub works: Vec<WorkRef>,
+    /// Number of entries in this page before any filtering, for guessing the number of pages
+    pub len: usize,
+    /// Number of works in the whole collection
+    pub total: usize,
+    pub info: Option<CollectionInfo>,
+}
+
+/// An endpoint returning a collection of works page by page
+#[async_trait]
+pub trait PagedSource: Send + Sync + 'static {
+    fn pagination(&self) -> Pagination;
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError>;
+}
+
+/// Fetch the first page for metadata, then stream all works while fetching up to `prefetch` pages ahead
+pub async fn paginate(
+    client: PixivClient,
+    source: Arc<dyn PagedSource>,
+    prefetch: usize,
+) -> Result<Collection, ApiError> {
+    let pagination = source.pagination();
+    let first = source.fetch(&client, pagination.cursor(0)).await?;
+
+    // Assume all pages are as full as the first one, except for the last
+    let page_len = match pagination {
+        Pagination::Offset { limit } => limit,
+        Pagination::Numbered => first.len,
+    };
+    let page_count = if page_len == 0 {
+        1
+    } else {
+        first.total.div_ceil(page_len)
+    };
+
+    let rest = stream::iter(1..page_count)
+        .map(move |i| {
+            let (client, source) = (client.clone(), source.clone());
+            async move { source.fetch(&client, pagination.cursor(i)).await }
+        })
+        .buffered(prefetch.max(1))
+        .map_ok(|p| stream::iter(p.works.into_iter().map(Ok)))
+        .try_flatten();
+
+    Ok(Collection {
+        info: first.info,
+        total: first.total,
+        works: Box::pin(stream::iter(first.works.into_iter().map(Ok)).chain(rest)),
+    })
+}
+
+// ---------- Sources
+
+/// Illusts of a series, in order
+pub struct Series {
+    pub series_id: u64,
+}
+
+#[async_trait]
+impl PagedSource for Series {
+    fn pagination(&self) -> Pagination {
+        Pagination::Numbered
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Page(page) = cursor else {
+            unreachable!("series are numbered")
+        };
+        let body = client.series(self.series_id, page).await?;
+
+        Ok(SourcePage {
+            len: body.page.series.len(),
+            total: body.page.total,
+            info: body
+                .illust_series
+                .into_iter()
+                .find(|i| i.id == self.series_id)
+                .filter(|i| !i.title.is_empty())
+                .map(|i| CollectionInfo {
+                    title: i.title,
+                    description: i.description,
+                    cover_url: Some(i.url).filter(|u| !u.is_empty()),
+                }),
+            works: body
+                .page
+                .series
+                .into_iter()
+                .map(|p| WorkRef {
+                    id: p.work_id,
+                    order: Some(p.order),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Illusts bookmarked by a user, without those that were removed
+pub struct UserBookmarks {
+    pub user_id: u64,
+    pub visibility: Visibility,
+}
+
+#[async_trait]
+impl PagedSource for UserBookmarks {
+    fn pagination(&self) -> Pagination {
+        Pagination::Offset {
+            limit: WORKS_PER_PAGE,
+        }
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Offset { offset, limit } = cursor else {
+            unreachable!("uses offsets")
+        };
+        let body = client
+            .user_bookmarks(self.user_id, offset, limit, self.visibility)
+            .await?;
+
+        Ok(SourcePage {
+            len: body.works.len(),
+            total: body.total,
+            info: None,
+            works: body
+                .works
+                .into_iter()
+                .filter(|w| !w.is_masked)
+                .map(|w| WorkRef::new(w.id))
+                .collect(),
+        })
+    }
+}
+
+/// Illusts and manga posted by a user with a tag
+pub struct UserWorksWithTag {
+    pub user_id: u64,
+    pub tag: String,
+}
+
+#[async_trait]
+impl PagedSource for UserWorksWithTag {
+    fn pagination(&self) -> Pagination {
+        Pagination::Offset {
+            limit: WORKS_PER_PAGE,
+        }
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Offset { offset, limit } = cursor else {
+            unreachable!("uses offsets")
+        };
+        let body = client
+            .user_works_with_tag(self.user_id, &self.tag, offset, limit)
+            .await?;
+
+        Ok(SourcePage {
+            len: body.works.len(),
+            total: body.total,
+            info: None,
+            works: body.works.into_iter().map(|w| WorkRef::new(w.id)).collect(),
+        })
+    }
+}
+
+/// Illusts and manga found by searching for a word
+pub struct TagSearch {
+    pub word: String,
+    /// How the word is matched, see `search::get`
+    pub s_mode: String,
+    /// Which works are included, see `search::get`
+    pub mode: String,
+}
+
+#[async_trait]
+impl PagedSource for TagSearch {
+    fn pagination(&self) -> Pagination {
+        Pagination::Numbered
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Page(page) = cursor else {
+            unreachable!("search is numbered")
+        };
+        let body = client
+            .search_artworks(&self.word, &self.s_mode, &self.mode, page)
+            .await?;
+        let results = body.illust_manga;
+
+        Ok(SourcePage {
+            len: results.data.len(),
+            total: results.total,
+            info: None,
+            works: results
+                .data
+                .into_iter()
+                .filter(|w| !w.is_ad_container && w.id != 0)
+                .map(|w| WorkRef::new(w.id))
+                .collect(),
+        })
+    }
+}
+
+/// Illusts of a ranking, from the first
+pub struct Ranking {
+    /// Kind of ranking, see `ranking::get`
+    pub mode: String,
+    /// Type of works, see `ranking::get`
+    pub content: String,
+    /// As `YYYYMMDD`, latest if not given
+    pub date: Option<String>,
+}
+
+#[async_trait]
+impl PagedSource for Ranking {
+    fn pagination(&self) -> Pagination {
+        Pagination::Numbered
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Page(page) = cursor else {
+            unreachable!("rankings are numbered")
+        };
+        let body = client
+            .ranking(&self.mode, &self.content, self.date.as_deref(), page)
+            .await?;
+
+        Ok(SourcePage {
+            len: body.contents.len(),
+            total: body.rank_total,
+            info: None,
+            works: body
+                .contents
+                .into_iter()
+                .map(|e| WorkRef {
+                    id: e.illust_id,
+                    order: Some(e.rank),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Novels of a series, in order
+pub struct NovelSeries {
+    pub series_id: u64,
+}
+
+#[async_trait]
+impl PagedSource for NovelSeries {
+    fn pagination(&self) -> Pagination {
+        Pagination::Offset {
+            limit: NOVELS_PER_PAGE,
+        }
+    }
+
+    async fn fetch(&self, client: &PixivClient, cursor: Cursor) -> Result<SourcePage, ApiError> {
+        let Cursor::Offset { offset, limit } = cursor else {
+            unreachable!("uses offsets")
+        };
+        let body = client
+            .novel_series_contents(self.series_id, offset, limit)
+            .await?;
+
+        // Description and total are only needed with the first page
+        let (info, total) = if offset == 0 {
+            let series = client.novel_series(self.series_id).await?;
+            let info = CollectionInfo {
+                title: series.title,
+                description: series.caption,
+                cover_url: None,
+            };
+            (Some(info), series.total)
+        } else {
+            (None, 0)
+        };
+
+        let novels = body.page.series_contents;
+        Ok(SourcePage {
+            len: novels.len(),
+            total,
+            info,
+            works: novels
+                .into_iter()
+                .map(|n| WorkRef {
+                    id: n.id,
+                    order: Some(n.series.content_order),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Illusts and manga posted by a user. All IDs come in a single request
+pub async fn user_posts(client: &PixivClient, user_id: u64) -> Result<Collection, ApiError> {
+    let profile = client.user_profile(user_id).await?;
+    let ids = profile.illusts.into_iter().chain(profile.manga).collect();
+
+    Ok(Collection::from_ids(ids))
+}
+
+// -----
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        gen_http_client::{Endpoints, Limits},
+        transport::MemoryTransport,
+    };
+
+    fn series_page(ids: &[u64], first_order: usize, total: usize) -> String {
+        let series: Vec<String> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| format!(r#"{{"workId":"{}","order":{}}}"#, id, first_order + i))
+            .collect();
+        format!(
+            r#"{{"error":false,"message":"","body":{{"illustSeries":[{{"id":"5","title":"Some series"}}],"page":{{"series":[{}],"total":{}}}}}}}"#,
+            series.join(","),
+            total
+        )
+    }
+
+    #[tokio::test]
+    async fn series_pages_are_streamed_in_order() {
+        let mut transport = MemoryTransport::default();
+        let pages: [&[u64]; 3] = [&[10, 11], &[12, 13], &[14]];
+        for (i, ids) in pages.iter().enumerate() {
+            transport.insert(
+                &format!("https://www.pixiv.net/ajax/series/5?p={}", i + 1),
+                StatusCode::OK,
+                series_page(ids, i * 2 + 1, 5),
+            );
+        }
+        let client = PixivClient::with_transport(
+            Arc::new(transport),
+            Limits::default(),
+            Endpoints::default(),
+            None,
+        );
+
+        let collection = paginate(client, Arc::new(Series { series_id: 5 }), 2)
+            .await
+            .unwrap();
+        let info = collection.info.unwrap();
+        assert_eq!(info.title, "Some series");
+        assert_eq!(collection.total, 5);
+
+        let works: Vec<WorkRef> = collection.works.try_collect().await.unwrap();
+        let ids: Vec<u64> = works.iter().map(|w| w.id).collect();
+        assert_eq!(ids, [10, 11, 12, 13, 14]);
+        assert_eq!(works[4].order, Some(5));
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File as StdFile, FileTimes},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use pixiv_util::api_calls::illust::IllustInfo;
+use tokio::task::{spawn_blocking, JoinSet};
+
+use crate::{
+    client_setup::setup_client,
+    incremental::walk_illust_files,
+    lock::DirLock,
+    output::{emit, message, Event},
+    FileDate, TouchParameters,
+};
+
+pub async fn do_touch_subcommand(params: TouchParameters) -> Result<()> {
+    let (client, _) =
+        setup_client(params.cookie_override, params.user_override, &params.client).await?;
+
+    let root = params.directory.unwrap_or_default();
+    let _lock = DirLock::acquire(&root, params.wait).await?;
+
+    // Find all files of each work
+    let mut works: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    walk_illust_files(&root, None, &mut |f| {
+        if !f.temporary {
+            works.entry(f.id).or_default().push(f.path.to_path_buf());
+        }
+    })?;
+
+    let mut set = JoinSet::new();
+    for (id, paths) in works {
+        let client = client.clone();
+        set.spawn(async move {
+            let date = match client.illust(id).await {
+                Ok(info) => work_date(&info, params.date)?,
+                Err(e) => {
+                    message!("{}: couldn't get date from server: {}", id, e);
+                    return Ok(0);
+                }
+            };
+            spawn_blocking(move || set_file_times(&paths, date)).await?
+        });
+    }
+
+    let mut nb_files = 0;
+    while let Some(r) = set.join_next().await {
+        nb_files += r??;
+    }
+
+    emit(Event::Touched { files: nb_files });
+    message!("Changed times of {} files", nb_files);
+
+    Ok(())
+}
+
+/// Date of a work, as chosen by the user
+pub fn work_date(info: &IllustInfo, which: FileDate) -> Result<SystemTime> {
+    let date = match which {
+        FileDate::Created => info.created_at(),
+        FileDate::Uploaded => info.uploaded_at(),
+    };
+    date.ok_or_else(|| anyhow!("invalid date for `{}`", info.illust_title))
+}
+
+/// Set modification and access times of files. Returns the number of files changed
+pub fn set_file_times(paths: &[impl AsRef<Path>], time: SystemTime) -> Result<usize> {
+    let times = FileTimes::new().set_accessed(time).set_modified(time);
+    for path in paths {
+        StdFile::options()
+            .write(true)
+            .open(path)?
+            .set_times(times)?;
+    }
+    Ok(paths.len())
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, pin::Pin, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use thiserror::Error;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, warn};
+
+/// A complete response, for API requests
+pub struct Response {
+    /// Where the response came from, after any redirects
+    pub url: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, TransportError>> + Send>>;
+
+/// A response whose body arrives in chunks, for downloads
+pub struct StreamingResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: ByteStream,
+}
+
+/// A request that couldn't be completed
+#[derive(Error, Debug)]
+pub enum TransportError {
+    /// Connection, TLS, proxy or timeout problem
+    #[error("http request failed")]
+    Http(#[from] reqwest::Error),
+}
+
+/// How HTTP GET requests are actually performed
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn get(&self, url: &str) -> Result<Response, TransportError>;
+
+    async fn get_stream(
+        &self,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<StreamingResponse, TransportError>;
+}
+
+// ---------- Network
+
+/// Real requests made with reqwest
+pub struct ReqwestTransport {
+    pub client: Client,
+}
+
+#[async_trait]
+impl Transport for ReqwestTransport {
+    async fn get(&self, url: &str) -> Result<Response, TransportError> {
+        let resp = self.client.get(url).send().await.inspect_err(|e| {
+            warn!(url, error = %e, "request failed");
+        })?;
+        let status = resp.status();
+        debug!(url, %status, "GET");
+        let final_url = resp.url().to_string();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+
+        Ok(Response {
+            url: final_url,
+            status,
+            headers,
+            body,
+        })
+    }
+
+    async fn get_stream(
+        &self,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<StreamingResponse, TransportError> {
+        let resp = self
+            .client
+            .get(url)
+            .timeout(timeout)
+            .send()
+            .await
+            .inspect_err(|e| warn!(url, error = %e, "request failed"))?;
+        let status = resp.status();
+        debug!(url, %status, "GET");
+        let headers = resp.headers().clone();
+        let body = resp.bytes_stream().map(|r| r.map_err(TransportError::from));
+
+        Ok(StreamingResponse {
+            status,
+            headers,
+            body: Box::pin(body),
+        })
+    }
+}
+
+// ---------- In memory
+
+/// Serves fixed responses by URL, without any network. Unknown URLs get a 404. Meant for tests
+#[derive(Default)]
+pub struct MemoryTransport {
+    responses: HashMap<String, (StatusCode, Bytes)>,
+}
+
+impl MemoryTransport {
+    pub fn insert(&mut self, url: &str, status: StatusCode, body: impl Into<Bytes>) {
+        self.responses
+            .insert(url.to_string(), (status, body.into()));
+    }
+
+    fn response(&self, url: &str) -> (StatusCode, Bytes) {
+        self.responses
+            .get(url)
+            .cloned()
+            .unwrap_or((StatusCode::NOT_FOUND, Bytes::new()))
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn get(&self, url: &str) -> Result<Response, TransportError> {
+        let (status, body) = self.response(url);
+
+        Ok(Response {
+            url: url.to_string(),
+            status,
+            headers: HeaderMap::new(),
+            body,
+        })
+    }
+
+    async fn get_stream(
+        &self,
+        url: &str,
+        _timeout: Duration,
+    ) -> Result<StreamingResponse, TransportError> {
+        let (status, body) = self.response(url);
+
+        Ok(StreamingResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Box::pin(tokio_stream::once(Ok(body))),
+        })
+    }
+}
@@ -7,7 +7,7 @@ use crate::{CreateUpdateFileParameters, DownloadIllustModes};
 pub static UPDATE_FILE: &str = ".pixiv_update";
 
 pub fn do_create_update_file_subcommand(params: CreateUpdateFileParameters) -> Result<()> {
-    if let DownloadIllustModes::Individual { illust_ids: _ } = &params.mode {
+    if let DownloadIllustModes::Individual { .. } = &params.mode {
         return Err(anyhow!(
             "Cannot create an update file for individual illusts"
         ));
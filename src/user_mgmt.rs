@@ -8,8 +8,14 @@ use std::{
 use anyhow::Result;
 use dirs::config_dir;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::UsersSubcommands;
+use pixiv_util::gen_http_client::NetworkSettings;
+
+use crate::{
+    output::{is_json, print_json},
+    UsersSubcommands,
+};
 
 // TODO: Automatically update cookie with server answers ?
 
@@ -61,6 +67,9 @@ pub fn get_user_id(cookie: &str) -> Option<u64> {
 struct UserDatabase {
     default_user: Option<String>,
     users: HashMap<String, String>,
+    /// Proxies and certificates specific to some users
+    #[serde(default)]
+    network: HashMap<String, NetworkSettings>,
 }
 
 impl UserDatabase {
@@ -95,22 +104,46 @@ pub async fn do_users_subcommand(s: UsersSubcommands) -> Result<()> {
 
     match s {
         // Requires just the path
-        UsersSubcommands::PrintPath => println!("{}", path.display()),
+        UsersSubcommands::PrintPath => {
+            if is_json() {
+                print_json(&json!({ "path": path }));
+            } else {
+                println!("{}", path.display());
+            }
+        }
         _ => {
             let mut db = UserDatabase::load_database(&path).unwrap_or_default();
             match s {
                 // Requires only reading the DB
                 UsersSubcommands::ListUsers => {
-                    for name in db.users.keys() {
-                        println!("{}", name);
+                    if is_json() {
+                        let users: Vec<_> = db
+                            .users
+                            .iter()
+                            .map(|(name, cookie)| {
+                                json!({
+                                    "username": name,
+                                    "pixiv_id": get_user_id(cookie),
+                                    "default": db.default_user.as_ref() == Some(name),
+                                })
+                            })
+                            .collect();
+                        print_json(&users);
+                    } else {
+                        for name in db.users.keys() {
+                            println!("{}", name);
+                        }
                     }
                 }
                 UsersSubcommands::PrintCookie { username: name } => match db.users.get(&name) {
+                    Some(c) if is_json() => print_json(&json!({ "username": name, "cookie": c })),
                     Some(c) => println!("{}", c),
                     None => return Err(anyhow::anyhow!("No such user in database !")),
                 },
                 UsersSubcommands::GetDefault => {
-                    if let Some(u) = db.default_user {
+                    if is_json() {
+                        print_json(&json!({ "username": db.default_user }));
+                    } else if let Some(u) = db.default_user {
                         println!("{}", u)
                     } else {
                         println!("No default user.")
@@ -118,6 +151,9 @@ pub async fn do_users_subcommand(s: UsersSubcommands) -> Result<()> {
                 }
                 UsersSubcommands::GetPixivID { username: name } => match db.users.get(&name) {
                     Some(c) => match get_user_id(c) {
+                        Some(i) if is_json() => {
+                            print_json(&json!({ "username": name, "pixiv_id": i }))
+                        }
                         Some(i) => println!("{}", i),
                         None => return Err(anyhow::anyhow!("Couldn't get user id from cookie !")),
                     },
@@ -137,6 +173,7 @@ pub async fn do_users_subcommand(s: UsersSubcommands) -> Result<()> {
                             if delete_default {
                                 db.default_user = None;
                             }
+                            db.network.remove(&username);
                             match db.users.remove(&username) {
                                 Some(_) => {}
                                 None => return Err(anyhow::anyhow!("No such user in database !")),
@@ -150,6 +187,13 @@ pub async fn do_users_subcommand(s: UsersSubcommands) -> Result<()> {
                             }
                         }
                         UsersSubcommands::RemoveDefault => db.default_user = None,
+                        UsersSubcommands::SetNetwork { username, network } => {
+                            if !db.users.contains_key(&username) {
+                                return Err(anyhow::anyhow!("No such user in database !"));
+                            }
+                            let current = db.network.remove(&username).unwrap_or_default();
+                            db.network.insert(username, network.applied_to(current)?);
+                        }
                         _ => {}
                     }
                     db.save_database(&path)?;
@@ -175,3 +219,17 @@ pub async fn retrieve_cookie(user_override: Option<String>) -> Result<Option<Str
         Ok(db.get_default_cookie().cloned())
     }
 }
+
+pub async fn retrieve_network_settings(user_override: Option<String>) -> Result<NetworkSettings> {
+    let path = get_db_file_path()?;
+    let db = UserDatabase::load_database(&path).unwrap_or_default();
+
+    let user = match user_override {
+        Some(u) => Some(u),
+        None => db.default_user.clone(),
+    };
+
+    Ok(user
+        .and_then(|u| db.network.get(&u).cloned())
+        .unwrap_or_default())
+}
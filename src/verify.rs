@@ -0,0 +1,313 @@
+use std::{
+    collections::BTreeMap,
+    fs::{remove_file, File as StdFile},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use pixiv_util::PixivClient;
+use serde::Serialize;
+use tokio::task::{spawn_blocking, JoinSet};
+
+use crate::{
+    archive::Archive,
+    client_setup::setup_client,
+    download::file::{filename_from_url, safe_dl_to, MAX_RETRIES, TIMEOUT},
+    incremental::{strip_order_prefix, walk_illust_files},
+    lock::DirLock,
+    output::{emit, message, Event},
+    VerifyParameters,
+};
+
+pub async fn do_verify_subcommand(params: VerifyParameters) -> Result<()> {
+    let (client, _) =
+        setup_client(params.cookie_override, params.user_override, &params.client).await?;
+
+    let root = params.directory.unwrap_or_default();
+    let _lock = DirLock::acquire(&root, params.wait).await?;
+
+    // Find everything on disk
+    let mut works: BTreeMap<u64, LocalIllust> = BTreeMap::new();
+    let mut orphans = Vec::new();
+    walk_illust_files(&root, None, &mut |f| {
+        if f.temporary {
+            orphans.push(f.path.to_path_buf());
+            return;
+        }
+        // Files without a page number (e.g. ugoira) can't be checked against the API
+        if let Some(page) = f.page {
+            let work = works.entry(f.id).or_default();
+            if work.dir.is_none() {
+                work.dir = f.path.parent().map(Path::to_path_buf);
+            }
+            work.pages.insert(page, f.path.to_path_buf());
+        }
+    })?;
+
+    for orphan in &orphans {
+        message!("Leftover temporary file: {}", orphan.display());
+        emit(Event::LeftoverFile { path: orphan });
+        if params.repair {
+            remove_file(orphan)?;
+        }
+    }
+
+    // Repaired works are hashed again
+    let archive = Arc::new(Archive::load(&root)?);
+
+    // Check all works in parallel
+    let mut set = JoinSet::new();
+    for (id, work) in works {
+        set.spawn(verify_one_illust(
+            client.clone(),
+            archive.clone(),
+            id,
+            work,
+            params.repair,
+        ));
+    }
+
+    let mut nb_problems = orphans.len();
+    let mut nb_repaired = if params.repair { orphans.len() } else { 0 };
+    while let Some(r) = set.join_next().await {
+        let checked = r??;
+        nb_problems += checked.problems;
+        nb_repaired += checked.repaired;
+    }
+
+    emit(Event::Verified {
+        problems: nb_problems,
+        repaired: nb_repaired,
+    });
+    if nb_problems == 0 {
+        message!("No problems found");
+    } else if params.repair {
+        message!("Repaired {} of {} problems", nb_repaired, nb_problems);
+    } else {
+        message!("Found {} problems", nb_problems);
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct LocalIllust {
+    /// Where the pages of this illust are
+    dir: Option<PathBuf>,
+    pages: BTreeMap<usize, PathBuf>,
+}
+
+/// What was found wrong with an illust, and how much of it was fixed
+#[derive(Default)]
+struct Checked {
+    problems: usize,
+    repaired: usize,
+}
+
+/// Check one illust against what the API reports, and re-download what is wrong if asked to
+async fn verify_one_illust(
+    client: PixivClient,
+    archive: Arc<Archive>,
+    illust_id: u64,
+    mut local: LocalIllust,
+    repair: bool,
+) -> Result<Checked> {
+    let pages = match client.illust_pages(illust_id).await {
+        Ok(p) => p,
+        Err(e) => {
+            message!("{}: couldn't get pages from server: {}", illust_id, e);
+            return Ok(Checked::default());
+        }
+    };
+
+    let nb_pages = pages.len();
+    let mut to_download = Vec::new();
+
+    for (page_index, page) in pages.into_iter().enumerate() {
+        let problem = match local.pages.get(&page_index) {
+            None => Some(ImageProblem::Missing),
+            Some(path) => check_image(path)?,
+        };
+
+        if let Some(problem) = problem {
+            message!("{} page {}: {}", illust_id, page_index, problem);
+            emit(Event::PageProblem {
+                illust_id,
+                page: page_index,
+                problem,
+            });
+            to_download.push((page_index, page.urls.original));
+        }
+    }
+
+    let mut checked = Checked {
+        problems: to_download.len(),
+        repaired: 0,
+    };
+
+    if repair && !to_download.is_empty() {
+        if let Some(dir) = local.dir.clone() {
+            // Missing pages are named like the others, e.g. with their position in a series
+            let prefix = local.pages.values().next().and_then(|p| {
+                let name = p.file_name()?.to_str()?;
+                Some(name[..name.len() - strip_order_prefix(name).len()].to_string())
+            });
+
+            let mut set = JoinSet::new();
+            for (page_index, url) in to_download {
+                let dest = match local.pages.get(&page_index) {
+                    Some(path) => path.clone(),
+                    None => dir.join(format!(
+                        "{}{}",
+                        prefix.as_deref().unwrap_or_default(),
+                        filename_from_url(&url)
+                    )),
+                };
+                let client = client.clone();
+                set.spawn(async move {
+                    let r =
+                        safe_dl_to(client, url, dest, MAX_RETRIES, Duration::from_secs(TIMEOUT))
+                            .await;
+                    (page_index, r)
+                });
+            }
+            while let Some(r) = set.join_next().await {
+                match r? {
+                    (page_index, Ok(path)) => {
+                        local.pages.insert(page_index, path);
+                        checked.repaired += 1;
+                    }
+                    (_, Err(e)) => message!("{}: couldn't download again: {}", illust_id, e),
+                }
+            }
+
+            // Archive must know the new files
+            if checked.repaired > 0 && archive.page_count(illust_id).is_some() {
+                let paths: Vec<_> = local.pages.into_values().collect();
+                spawn_blocking(move || archive.refresh(illust_id, nb_pages, &paths)).await??;
+            }
+        }
+    }
+
+    Ok(checked)
+}
+
+// ---------- Image checks
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageProblem {
+    Missing,
+    Empty,
+    UnknownFormat,
+    Truncated,
+}
+
+impl std::fmt::Display for ImageProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ImageProblem::Missing => "missing",
+            ImageProblem::Empty => "empty file",
+            ImageProblem::UnknownFormat => "not a recognized image",
+            ImageProblem::Truncated => "truncated",
+        })
+    }
+}
+
+/// Look at the start and end of an image file to make sure it was completely downloaded
+fn check_image(path: &Path) -> Result<Option<ImageProblem>> {
+    let mut file = StdFile::open(path)?;
+    let len = file.metadata()?.len();
+
+    if len == 0 {
+        return Ok(Some(ImageProblem::Empty));
+    }
+    if len < 16 {
+        return Ok(Some(ImageProblem::Truncated));
+    }
+
+    let mut head = [0; 12];
+    file.read_exact(&mut head)?;
+    let mut tail = [0; 12];
+    file.seek(SeekFrom::End(-12))?;
+    file.read_exact(&mut tail)?;
+
+    Ok(image_problem(&head, &tail, len))
+}
+
+/// Tell if an image is complete from its first and last bytes, and its size
+fn image_problem(head: &[u8; 12], tail: &[u8; 12], len: u64) -> Option<ImageProblem> {
+    let complete = if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        // JPEG ends with End Of Image marker
+        tail.ends_with(&[0xFF, 0xD9])
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // PNG ends with an empty IEND chunk
+        *tail == *b"\0\0\0\0IEND\xae\x42\x60\x82"
+    } else if head.starts_with(b"GIF8") {
+        // GIF ends with trailer byte
+        tail[11] == 0x3B
+    } else if head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        // WebP states its own size in the RIFF header
+        u32::from_le_bytes([head[4], head[5], head[6], head[7]]) as u64 + 8 == len
+    } else if head.starts_with(b"PK\x03\x04") {
+        // Zip archives (ugoira frames) are not checked
+        return None;
+    } else {
+        return Some(ImageProblem::UnknownFormat);
+    };
+
+    if complete {
+        None
+    } else {
+        Some(ImageProblem::Truncated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First and last 12 bytes of a file
+    fn ends(head: &[u8], tail: &[u8]) -> ([u8; 12], [u8; 12]) {
+        let mut h = [0; 12];
+        h[..head.len()].copy_from_slice(head);
+        let mut t = [0; 12];
+        t[12 - tail.len()..].copy_from_slice(tail);
+        (h, t)
+    }
+
+    #[test]
+    fn images_are_checked() {
+        let png = b"\x89PNG\r\n\x1a\n";
+        let iend = b"\0\0\0\0IEND\xae\x42\x60\x82";
+        let mut webp = *b"RIFF\0\0\0\0WEBP";
+        webp[4..8].copy_from_slice(&92u32.to_le_bytes());
+
+        let cases = [
+            (ends(&[0xFF, 0xD8, 0xFF], &[0xFF, 0xD9]), 100, None),
+            (
+                ends(&[0xFF, 0xD8, 0xFF], &[0x12, 0x34]),
+                100,
+                Some(ImageProblem::Truncated),
+            ),
+            (ends(png, iend), 100, None),
+            (ends(png, b"IDAT"), 100, Some(ImageProblem::Truncated)),
+            (ends(b"GIF89a", &[0x3B]), 100, None),
+            (ends(b"GIF89a", &[0x00]), 100, Some(ImageProblem::Truncated)),
+            (ends(&webp, &[]), 100, None),
+            (ends(&webp, &[]), 60, Some(ImageProblem::Truncated)),
+            (ends(b"PK\x03\x04", &[]), 100, None),
+            (
+                ends(b"<html>", b"</html>"),
+                100,
+                Some(ImageProblem::UnknownFormat),
+            ),
+        ];
+        for (i, ((head, tail), len, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(image_problem(&head, &tail, len), expected, "case {}", i);
+        }
+    }
+}